@@ -1,7 +1,4 @@
-#![feature(specialization)]
-#![feature(core_intrinsics)]
-#![feature(vec_into_raw_parts)]
-// #![feature(const_generics)]
+#![allow(clippy::type_complexity, clippy::too_many_arguments)]
 
 #[cfg(not(target_env = "msvc"))]
 use jemallocator::Jemalloc;
@@ -1,37 +1,149 @@
+use rand::distributions::{Distribution, Standard};
 use rand::{rngs::StdRng, Rng, SeedableRng};
-use std::sync::Arc;
 
-use palm::palm::notthreadsafe::NotThreadSafe;
+use palm::palm::map::PalmMap;
+use palm::palm::node::LeafLayout;
 use palm::palm::query::Query;
-use palm::palm::tree::*;
-use palm::palm::worker::*;
+use palm::palm::util::SearchKey;
 
-type KeyType = i32;
-const KEY_RANGE: KeyType = 1073741824;
+type ValueType = i32;
 
 #[allow(non_snake_case)]
 fn main() {
     let args: Vec<_> = std::env::args().collect();
     if args.len() < 4 {
-        println!("Usage: <NUM_THREADS> <BATCH_SIZE> <NUM_BATCHES>");
+        println!(
+            "Usage: <NUM_THREADS> <BATCH_SIZE> <NUM_BATCHES> [sorted|unsorted] [i32|u32|i64|u64|bytes] \
+             [8|16|32|38|64|128|sweep]"
+        );
         return;
     }
 
     let NUM_THREADS: usize = args[1].parse().unwrap();
     let BATCH_SIZE: usize = args[2].parse().unwrap();
     let NUM_BATCHES: usize = args[3].parse().unwrap();
+    let LAYOUT = match args.get(4).map(|s| s.as_str()) {
+        Some("unsorted") => LeafLayout::Unsorted,
+        Some("sorted") | None => LeafLayout::Sorted,
+        Some(other) => {
+            println!("Unknown leaf layout: {}", other);
+            return;
+        }
+    };
+    let FANOUT = args.get(6).map_or("38", |s| s.as_str());
+    match args.get(5).map(|s| s.as_str()) {
+        Some("i32") | None => with_key::<i32>(
+            NUM_THREADS,
+            BATCH_SIZE,
+            NUM_BATCHES,
+            LAYOUT,
+            FANOUT,
+            uniform,
+        ),
+        Some("u32") => with_key::<u32>(
+            NUM_THREADS,
+            BATCH_SIZE,
+            NUM_BATCHES,
+            LAYOUT,
+            FANOUT,
+            uniform,
+        ),
+        Some("i64") => with_key::<i64>(
+            NUM_THREADS,
+            BATCH_SIZE,
+            NUM_BATCHES,
+            LAYOUT,
+            FANOUT,
+            uniform,
+        ),
+        Some("u64") => with_key::<u64>(
+            NUM_THREADS,
+            BATCH_SIZE,
+            NUM_BATCHES,
+            LAYOUT,
+            FANOUT,
+            uniform,
+        ),
+        Some("bytes") => {
+            with_key::<Vec<u8>>(NUM_THREADS, BATCH_SIZE, NUM_BATCHES, LAYOUT, FANOUT, path)
+        }
+        Some(other) => println!("Unknown key type: {}", other),
+    }
+}
+
+// uniform over the whole key type
+fn uniform<K>(rng: &mut StdRng) -> K
+where
+    Standard: Distribution<K>,
+{
+    rng.gen::<K>()
+}
 
-    let tree = Arc::new(NotThreadSafe::new(Palm::<KeyType, KeyType>::new(
-        NUM_THREADS,
-    )));
-    let mut wrapper = PalmWrapper::new(tree, NUM_THREADS);
+// path-like byte strings sharing long prefixes, as in a secondary index
+fn path(rng: &mut StdRng) -> Vec<u8> {
+    let (tenant, id) = (rng.gen_range(0, 16), rng.gen::<u32>());
+    format!(
+        "/tenants/{:04}/regions/eu-west-1/buckets/analytics-archive/objects/{:010}",
+        tenant, id
+    )
+    .into_bytes()
+}
+
+// the fanout is a const generic, so only a fixed set can be picked at runtime
+macro_rules! sweep {
+    ($fanout:expr, $k:ty, $args:tt, $($f:literal),*) => {
+        match $fanout {
+            "sweep" => {
+                $(run::<$k, $f> $args;)*
+            }
+            $(stringify!($f) => run::<$k, $f> $args,)*
+            other => println!("Unsupported fanout: {}", other),
+        }
+    };
+}
+
+#[allow(non_snake_case)]
+fn with_key<K>(
+    NUM_THREADS: usize,
+    BATCH_SIZE: usize,
+    NUM_BATCHES: usize,
+    LAYOUT: LeafLayout,
+    FANOUT: &str,
+    gen: fn(&mut StdRng) -> K,
+) where
+    K: 'static + SearchKey + Clone + std::fmt::Debug + Sync + Send,
+{
+    sweep!(
+        FANOUT,
+        K,
+        (NUM_THREADS, BATCH_SIZE, NUM_BATCHES, LAYOUT, gen),
+        8,
+        16,
+        32,
+        38,
+        64,
+        128
+    );
+}
+
+#[allow(non_snake_case)]
+fn run<K, const F: usize>(
+    NUM_THREADS: usize,
+    BATCH_SIZE: usize,
+    NUM_BATCHES: usize,
+    LAYOUT: LeafLayout,
+    gen: fn(&mut StdRng) -> K,
+) where
+    K: 'static + SearchKey + Clone + std::fmt::Debug + Sync + Send,
+{
+    let mut map = PalmMap::<K, ValueType, F>::with_layout(NUM_THREADS, LAYOUT);
 
     let seed = [1u8; 32];
     let mut rng: StdRng = SeedableRng::from_seed(seed);
     for _ in 0..NUM_BATCHES {
-        let mut queries: Vec<_> = (0..BATCH_SIZE)
+        let queries: Vec<_> = (0..BATCH_SIZE)
             .map(|_| {
-                let k = rng.gen_range(1, KEY_RANGE);
+                let k = gen(&mut rng);
                 if rng.gen::<bool>() {
                     Query::Insertion {
                         k,
@@ -42,10 +154,15 @@ fn main() {
                 }
             })
             .collect();
-        wrapper.run_batch(&mut queries);
+        map.apply_batch(&queries);
     }
     println!(
-        "[Time] Sequential: {} μs, Parallel: {} μs",
-        wrapper.seq_time, wrapper.par_time
+        "[Time] Key: {}, Fanout: {}, Layout: {:?}, SIMD: {:?}, Sequential: {} μs, Parallel: {} μs",
+        std::any::type_name::<K>(),
+        F,
+        LAYOUT,
+        map.simd_level(),
+        map.pool().seq_time,
+        map.pool().par_time
     );
 }
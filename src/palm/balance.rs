@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+
+use super::nodeptr::NodePtr;
+use super::notthreadsafe::NotThreadSafe;
+use super::sync::atomic::{AtomicUsize, Ordering};
+use super::tree::{ModifMap, QueryMap, Tagged};
+
+// Estimated work of a chunk: its queries, plus one per leaf for reading
+//   the leaf and writing it back
+const CHUNK_WORK: usize = 64;
+
+// A run of leaves with their queries, cut out of a thread's queue
+type Chunk<K, V, const F: usize> = [(NodePtr<K, V, F>, NotThreadSafe<Vec<Tagged<K, V>>>)];
+
+// The leaf queues of `Partitioning::Leaves`, cut into chunks of whole
+//   leaves. A thread takes chunks from its own queue first, then from the
+//   following threads' queues. The modifications of each chunk are kept
+//   apart, so that every thread can gather those of its own queue in leaf
+//   order, as if it had applied all of them itself.
+pub(crate) struct LeafChunks<K, V, const F: usize>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    // per thread, disjoint slices of its queue, split off by the owner
+    chunks: Vec<NotThreadSafe<Vec<*const Chunk<K, V, F>>>>,
+    // per thread, the next chunk to take
+    next: Vec<AtomicUsize>,
+    // per thread and chunk, written by the thread that took the chunk
+    modifs: Vec<NotThreadSafe<Vec<NotThreadSafe<ModifMap<K, V, F>>>>>,
+}
+
+impl<K, V, const F: usize> LeafChunks<K, V, F>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub(crate) fn new(num_threads: usize) -> Self {
+        Self {
+            chunks: (0..num_threads)
+                .map(|_| NotThreadSafe::new(Vec::new()))
+                .collect(),
+            next: (0..num_threads).map(|_| AtomicUsize::new(0)).collect(),
+            modifs: (0..num_threads)
+                .map(|_| NotThreadSafe::new(Vec::new()))
+                .collect(),
+        }
+    }
+
+    // Cuts the queue of thread `index` once `redistribute_work` is done
+    //   with it; its first leaf is left out if the previous thread took it.
+    //   The chunks can be taken after the next barrier, and stay valid until
+    //   the queue is next modified.
+    pub(crate) fn plan(
+        &self,
+        index: usize,
+        leaves: &QueryMap<K, V, F>,
+        their_last: NodePtr<K, V, F>,
+    ) {
+        let chunks = self.chunks[index].get_mut();
+        chunks.clear();
+        // neighbours may still be reading the queue, so it is cut where it
+        //   lies rather than made contiguous; a chunk also ends where the
+        //   ring buffer wraps
+        let (mut front, back) = leaves.as_slices();
+        if let Some((node_ptr, _)) = front.first() {
+            if *node_ptr == their_last {
+                front = &front[1..];
+            }
+        }
+        for mut rest in [front, back] {
+            while !rest.is_empty() {
+                let mut work = 0;
+                let len = rest
+                    .iter()
+                    .position(|(_, queries)| {
+                        work += queries.get().len() + 1;
+                        work >= CHUNK_WORK
+                    })
+                    .map_or(rest.len(), |i| i + 1);
+                let (chunk, tail) = rest.split_at(len);
+                chunks.push(chunk as *const Chunk<K, V, F>);
+                rest = tail;
+            }
+        }
+
+        let modifs = self.modifs[index].get_mut();
+        if modifs.len() < chunks.len() {
+            modifs.resize_with(chunks.len(), || NotThreadSafe::new(VecDeque::new()));
+        }
+        self.next[index].store(0, Ordering::Relaxed);
+    }
+
+    // The next chunk for thread `index` as (owner, chunk, its leaves), or
+    //   `None` once every queue is empty
+    pub(crate) fn take<'a>(&self, index: usize) -> Option<(usize, usize, &'a Chunk<K, V, F>)> {
+        let num_threads = self.next.len();
+        (0..num_threads)
+            .map(|i| (index + i) % num_threads)
+            .find_map(|owner| {
+                let chunks = self.chunks[owner].get();
+                // looked at first, so that empty queues are not written to
+                if self.next[owner].load(Ordering::Relaxed) >= chunks.len() {
+                    return None;
+                }
+                let chunk = self.next[owner].fetch_add(1, Ordering::Relaxed);
+                let leaves = *chunks.get(chunk)?;
+                // each chunk is handed out once, so its queries are only
+                //   taken by one thread
+                Some((owner, chunk, unsafe { &*leaves }))
+            })
+    }
+
+    // Where the modifications of a chunk go, emptied by the taker
+    pub(crate) fn modifs(&self, owner: usize, chunk: usize) -> &mut ModifMap<K, V, F> {
+        self.modifs[owner].get()[chunk].get_mut()
+    }
+
+    // Moves the modifications of thread `index`'s chunks to `next_map`,
+    //   once every chunk is applied
+    pub(crate) fn gather(&self, index: usize, next_map: &mut ModifMap<K, V, F>) {
+        next_map.clear();
+        let chunks = self.chunks[index].get().len();
+        for modifs in &self.modifs[index].get()[..chunks] {
+            for (parent, modifs) in modifs.get_mut().drain(..) {
+                // a parent may span the end of a chunk
+                match next_map.back_mut() {
+                    Some((last, prev)) if *last == parent => {
+                        prev.get_mut().append(modifs.get_mut())
+                    }
+                    _ => next_map.push_back((parent, modifs)),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use super::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use super::sync::{hint, thread, Condvar, Mutex};
+
+// Spins for SPINS steps, doubling the pause each time, then yields for
+//   YIELDS steps, then parks until a neighbour publishes
+#[cfg(not(feature = "loom"))]
+const SPINS: u32 = 7;
+#[cfg(not(feature = "loom"))]
+const YIELDS: u32 = 4;
+// every spin is a branch for loom, one of each covers the same orderings
+#[cfg(feature = "loom")]
+const SPINS: u32 = 1;
+#[cfg(feature = "loom")]
+const YIELDS: u32 = 1;
+
+// A wakeup token like the one of `Thread::park`, but private to the
+//   handshake: the thread may also be parked in a channel or a barrier,
+//   which must not take the handshake's wakeups (loom treats them as errors)
+struct Parker {
+    token: Mutex<bool>,
+    unparked: Condvar,
+}
+
+impl Parker {
+    fn new() -> Self {
+        Self {
+            token: Mutex::new(false),
+            unparked: Condvar::new(),
+        }
+    }
+
+    // Returns at once if unparked since the last call
+    fn park(&self) {
+        let mut token = self.token.lock().unwrap();
+        while !*token {
+            token = self.unparked.wait(token).unwrap();
+        }
+        *token = false;
+    }
+
+    fn unpark(&self) {
+        *self.token.lock().unwrap() = true;
+        self.unparked.notify_one();
+    }
+}
+
+// A pointer published for one round of the handshake. The round is
+//   written after the pointer, with release ordering, so a reader that
+//   acquires the round also sees the pointer and everything the writer did
+//   before publishing it (i.e. its modifications of the tree and queues).
+struct Slot<T> {
+    ptr: AtomicPtr<T>,
+    round: AtomicUsize,
+}
+
+impl<T> Slot<T> {
+    fn new() -> Self {
+        Self {
+            ptr: AtomicPtr::new(std::ptr::null_mut()),
+            round: AtomicUsize::new(0),
+        }
+    }
+
+    fn publish(&self, round: usize, ptr: *mut T) {
+        self.ptr.store(ptr, Ordering::Relaxed);
+        self.round.store(round, Ordering::Release);
+    }
+
+    fn read(&self, round: usize) -> Option<*mut T> {
+        if self.round.load(Ordering::Acquire) == round {
+            Some(self.ptr.load(Ordering::Relaxed))
+        } else {
+            None
+        }
+    }
+}
+
+// The point-to-point synchronization between the levels of a batch:
+//   every thread tells thread i-1 the first node and thread i+1 the last
+//   node it holds, and learns theirs. Rounds are numbered from 1 and each
+//   thread only needs two slots per side: a thread can't finish round r+1
+//   before both neighbours have finished round r, i.e. read its round-r
+//   slots, so round r+2 may reuse them.
+pub struct Handshake<T> {
+    first: Vec<[Slot<T>; 2]>,
+    last: Vec<[Slot<T>; 2]>,
+    parkers: Vec<Parker>,
+}
+
+unsafe impl<T> Send for Handshake<T> {}
+unsafe impl<T> Sync for Handshake<T> {}
+
+impl<T> Handshake<T> {
+    pub fn new(num_threads: usize) -> Self {
+        Self {
+            first: (0..num_threads)
+                .map(|_| [Slot::new(), Slot::new()])
+                .collect(),
+            last: (0..num_threads)
+                .map(|_| [Slot::new(), Slot::new()])
+                .collect(),
+            parkers: (0..num_threads).map(|_| Parker::new()).collect(),
+        }
+    }
+
+    // Round `round` for thread `index`, returning (their_first, their_last),
+    //   null at the ends. `None` for `my_first` (`my_last`) forwards what
+    //   thread i+1 (i-1) sends instead, as for a thread holding no nodes.
+    pub fn exchange(
+        &self,
+        index: usize,
+        round: usize,
+        mut my_first: Option<*mut T>,
+        mut my_last: Option<*mut T>,
+    ) -> (*mut T, *mut T) {
+        let num_threads = self.first.len();
+        let slot = round % 2;
+        let (mut their_first, mut their_last) = (None, None);
+        let (mut sent_first, mut sent_last) = (false, false);
+        let mut step = 0;
+        loop {
+            if let (Some(ptr), false) = (my_first, sent_first) {
+                self.first[index][slot].publish(round, ptr);
+                sent_first = true;
+                if index > 0 {
+                    self.parkers[index - 1].unpark();
+                }
+            }
+            if let (Some(ptr), false) = (my_last, sent_last) {
+                self.last[index][slot].publish(round, ptr);
+                sent_last = true;
+                if index + 1 < num_threads {
+                    self.parkers[index + 1].unpark();
+                }
+            }
+            if their_first.is_none() {
+                their_first = if index + 1 == num_threads {
+                    Some(std::ptr::null_mut())
+                } else {
+                    self.first[index + 1][slot].read(round)
+                };
+                my_first = my_first.or(their_first);
+            }
+            if their_last.is_none() {
+                their_last = if index == 0 {
+                    Some(std::ptr::null_mut())
+                } else {
+                    self.last[index - 1][slot].read(round)
+                };
+                my_last = my_last.or(their_last);
+            }
+
+            match (their_first, their_last) {
+                (Some(first), Some(last)) if sent_first && sent_last => return (first, last),
+                // whatever came in is published on the next pass, without waiting
+                _ if my_first.is_some() != sent_first || my_last.is_some() != sent_last => {}
+                _ => {
+                    self.snooze(index, step);
+                    step += 1;
+                }
+            }
+        }
+    }
+
+    fn snooze(&self, index: usize, step: u32) {
+        if step < SPINS {
+            for _ in 0..1 << step {
+                hint::spin_loop();
+            }
+        } else if step < SPINS + YIELDS {
+            thread::yield_now();
+        } else {
+            // returns at once if a neighbour published since the last look
+            self.parkers[index].park();
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::fmt;
+
+use super::node::{LeafLayout, Node};
+use super::nodeptr::NodePtr;
+use super::tree::Palm;
+use super::util::{RawPointerOps, SearchKey};
+
+// A broken invariant, found by `Palm::check_invariants`. Nodes are named by
+//   their path, the child indices leading to them from the root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvariantError<K> {
+    // two keys out of order within a node (or equal, in an unsorted leaf)
+    KeyOrder {
+        path: Vec<usize>,
+        prev: K,
+        next: K,
+    },
+    // a key outside [lo, hi), the range the separators above give its node
+    SeparatorBounds {
+        path: Vec<usize>,
+        key: K,
+        lo: Option<K>,
+        hi: Option<K>,
+    },
+    // a node whose parent pointer is not the node holding it (or is not
+    //   null, for the root)
+    ParentPointer {
+        path: Vec<usize>,
+    },
+    // a leaf at another distance from the root than the first leaf
+    LeafLevel {
+        path: Vec<usize>,
+        height: usize,
+        expected: usize,
+    },
+    // a `level` field that is not one less than the parent's, or not 1 in
+    //   a leaf
+    Level {
+        path: Vec<usize>,
+        level: usize,
+        expected: usize,
+    },
+    // a node with more than `MAX_LEN` keys, or fewer than `MIN_LEN` below
+    //   the root
+    Fill {
+        path: Vec<usize>,
+        len: usize,
+    },
+    // an internal node without one child more than keys, or a leaf
+    //   without one value per key
+    Children {
+        path: Vec<usize>,
+        keys: usize,
+        children: usize,
+    },
+    // a leaf whose `next` is not the leaf to its right
+    SiblingLink {
+        path: Vec<usize>,
+    },
+    // an internal root without keys, i.e. with a single child: the tree
+    //   should have shrunk
+    KeylessRoot {
+        level: usize,
+    },
+    // `depth` differs from the height of the tree
+    Depth {
+        depth: usize,
+        height: usize,
+    },
+}
+
+impl<K: fmt::Debug> fmt::Display for InvariantError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::KeyOrder { path, prev, next } => {
+                write!(f, "{:?} before {:?} at {:?}", prev, next, path)
+            }
+            Self::SeparatorBounds { path, key, lo, hi } => {
+                write!(f, "{:?} at {:?} is outside [{:?}, {:?})", key, path, lo, hi)
+            }
+            Self::ParentPointer { path } => write!(f, "wrong parent pointer at {:?}", path),
+            Self::LeafLevel {
+                path,
+                height,
+                expected,
+            } => write!(
+                f,
+                "leaf at {:?} is {} levels deep, not {}",
+                path, height, expected
+            ),
+            Self::Level {
+                path,
+                level,
+                expected,
+            } => write!(
+                f,
+                "node at {:?} has level {}, not {}",
+                path, level, expected
+            ),
+            Self::Fill { path, len } => write!(f, "node at {:?} holds {} keys", path, len),
+            Self::Children {
+                path,
+                keys,
+                children,
+            } => write!(
+                f,
+                "node at {:?} has {} keys and {} children",
+                path, keys, children
+            ),
+            Self::SiblingLink { path } => write!(f, "wrong sibling link at {:?}", path),
+            Self::KeylessRoot { level } => {
+                write!(f, "internal root at level {} has no keys", level)
+            }
+            Self::Depth { depth, height } => {
+                write!(f, "depth is {}, but the tree is {} high", depth, height)
+            }
+        }
+    }
+}
+
+impl<K: fmt::Debug> std::error::Error for InvariantError<K> {}
+
+impl<K, V, const F: usize> Palm<K, V, F>
+where
+    K: 'static + SearchKey + Clone + std::fmt::Debug,
+    V: 'static + Clone + PartialEq + std::fmt::Debug,
+{
+    // Checks the whole structure, returning the first broken invariant;
+    //   only valid while no batch is running. Linear in the size of the
+    //   tree, so cheap enough to run after every batch in debug builds.
+    pub fn check_invariants(&self) -> Result<(), InvariantError<K>> {
+        if !self.root.get().parent.is_null() {
+            return Err(InvariantError::ParentPointer { path: vec![] });
+        }
+        let root = self.root.get();
+        if !root.holds_values() && root.is_empty() {
+            return Err(InvariantError::KeylessRoot { level: root.level });
+        }
+        let mut leaves = Vec::new();
+        self.check_node(self.root, &mut vec![], None, None, &mut leaves)?;
+
+        let height = leaves[0].1.len() + 1;
+        if self.depth != height || self.root.get().level != height {
+            return Err(InvariantError::Depth {
+                depth: self.depth,
+                height,
+            });
+        }
+        for pair in leaves.windows(2) {
+            let ((left, path), (right, _)) = (&pair[0], &pair[1]);
+            if left.get().next != *right {
+                return Err(InvariantError::SiblingLink { path: path.clone() });
+            }
+        }
+        let (last, path) = leaves.last().unwrap();
+        if !last.get().next.is_null() {
+            return Err(InvariantError::SiblingLink { path: path.clone() });
+        }
+        Ok(())
+    }
+
+    fn check_node(
+        &self,
+        node_ptr: NodePtr<K, V, F>,
+        path: &mut Vec<usize>,
+        lo: Option<&K>,
+        hi: Option<&K>,
+        leaves: &mut Vec<(NodePtr<K, V, F>, Vec<usize>)>,
+    ) -> Result<(), InvariantError<K>> {
+        let node = node_ptr.get();
+        let len = node.len();
+        if len > Node::<K, V, F>::MAX_LEN || (!path.is_empty() && len < Node::<K, V, F>::MIN_LEN) {
+            return Err(InvariantError::Fill {
+                path: path.clone(),
+                len,
+            });
+        }
+        for key in node.keys.iter() {
+            if lo.is_some_and(|lo| key < lo) || hi.is_some_and(|hi| key >= hi) {
+                return Err(InvariantError::SeparatorBounds {
+                    path: path.clone(),
+                    key: key.clone(),
+                    lo: lo.cloned(),
+                    hi: hi.cloned(),
+                });
+            }
+        }
+        // unsorted leaves only have to hold distinct keys
+        let mut keys: Vec<&K> = node.keys.iter().collect();
+        if node.holds_values() && self.layout == LeafLayout::Unsorted {
+            keys.sort();
+        }
+        for pair in keys.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(InvariantError::KeyOrder {
+                    path: path.clone(),
+                    prev: pair[0].clone(),
+                    next: pair[1].clone(),
+                });
+            }
+        }
+
+        if node.holds_values() {
+            if node.level != 1 {
+                return Err(InvariantError::Level {
+                    path: path.clone(),
+                    level: node.level,
+                    expected: 1,
+                });
+            }
+            if node.vals().len() != len {
+                return Err(InvariantError::Children {
+                    path: path.clone(),
+                    keys: len,
+                    children: node.vals().len(),
+                });
+            }
+            if let Some((_, first)) = leaves.first() {
+                if first.len() != path.len() {
+                    return Err(InvariantError::LeafLevel {
+                        path: path.clone(),
+                        height: path.len() + 1,
+                        expected: first.len() + 1,
+                    });
+                }
+            }
+            leaves.push((node_ptr, path.clone()));
+            return Ok(());
+        }
+
+        let level = node.level;
+        let children = node.children().to_vec();
+        if children.len() != len + 1 {
+            return Err(InvariantError::Children {
+                path: path.clone(),
+                keys: len,
+                children: children.len(),
+            });
+        }
+        let mut child_lo = lo;
+        for (i, child) in children.into_iter().enumerate() {
+            let child_hi = if i < len { Some(&node.keys[i]) } else { hi };
+            path.push(i);
+            if child.get().parent != node_ptr {
+                return Err(InvariantError::ParentPointer { path: path.clone() });
+            }
+            if child.get().level + 1 != level {
+                return Err(InvariantError::Level {
+                    path: path.clone(),
+                    level: child.get().level,
+                    expected: level.saturating_sub(1),
+                });
+            }
+            self.check_node(child, path, child_lo, child_hi, leaves)?;
+            path.pop();
+            child_lo = child_hi;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+use std::ops::Bound;
+use std::vec;
+
+use super::node::{Node, DEFAULT_FANOUT};
+use super::nodeptr::NodePtr;
+use super::util::RawPointerOps;
+
+// Ordered walk over the leaves of a tree no batch is running on, from both
+//   ends. The front follows the sibling links and the back climbs through
+//   the parents, one leaf at a time, until they meet or pass the bounds; the
+//   entries of each leaf are sorted once it is reached, since unsorted
+//   leaves keep them in insertion order.
+pub struct Iter<'a, K, V, const F: usize = DEFAULT_FANOUT> {
+    // the leaves left to walk, both null once they are all walked
+    first: NodePtr<K, V, F>,
+    last: NodePtr<K, V, F>,
+    lo: Bound<K>,
+    hi: Bound<K>,
+    front: vec::IntoIter<(&'a K, &'a V)>,
+    back: vec::IntoIter<(&'a K, &'a V)>,
+}
+
+impl<'a, K: Ord, V, const F: usize> Iter<'a, K, V, F> {
+    // `first` and `last` are the leaves `lo` and `hi` route to
+    pub(crate) fn new(
+        first: NodePtr<K, V, F>,
+        last: NodePtr<K, V, F>,
+        lo: Bound<K>,
+        hi: Bound<K>,
+    ) -> Self {
+        Self {
+            first,
+            last,
+            lo,
+            hi,
+            front: Vec::new().into_iter(),
+            back: Vec::new().into_iter(),
+        }
+    }
+
+    fn entries(&self, leaf: &'a Node<K, V, F>) -> vec::IntoIter<(&'a K, &'a V)> {
+        let bounds = (self.lo.as_ref(), self.hi.as_ref());
+        let mut entries: Vec<_> = leaf
+            .keys
+            .iter()
+            .zip(leaf.vals().iter())
+            .filter(|(k, _)| std::ops::RangeBounds::contains(&bounds, *k))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        entries.into_iter()
+    }
+
+    fn next_leaf(&mut self) -> Option<&'a Node<K, V, F>> {
+        if self.first.is_null() {
+            return None;
+        }
+        let node: &'a Node<K, V, F> = self.first.get();
+        // keys never decrease from one leaf to the next
+        let past_hi = node.keys.iter().any(|k| match &self.hi {
+            Bound::Included(hi) => k > hi,
+            Bound::Excluded(hi) => k >= hi,
+            Bound::Unbounded => false,
+        });
+        if self.first == self.last || past_hi {
+            self.done();
+        } else {
+            self.first = node.next;
+        }
+        Some(node)
+    }
+
+    fn next_leaf_back(&mut self) -> Option<&'a Node<K, V, F>> {
+        if self.last.is_null() {
+            return None;
+        }
+        let node: &'a Node<K, V, F> = self.last.get();
+        let below_lo = node.keys.iter().any(|k| match &self.lo {
+            Bound::Included(lo) => k < lo,
+            Bound::Excluded(lo) => k <= lo,
+            Bound::Unbounded => false,
+        });
+        if self.first == self.last || below_lo {
+            self.done();
+        } else {
+            self.last = prev_leaf(self.last);
+        }
+        Some(node)
+    }
+
+    fn done(&mut self) {
+        self.first = NodePtr::new(std::ptr::null_mut());
+        self.last = NodePtr::new(std::ptr::null_mut());
+    }
+}
+
+// The leaf before `leaf`, or null for the first one: up to the first
+//   ancestor with a left sibling, then down the sibling's last children
+fn prev_leaf<K, V, const F: usize>(leaf: NodePtr<K, V, F>) -> NodePtr<K, V, F> {
+    let mut node_ptr = leaf;
+    loop {
+        let parent = node_ptr.get().parent;
+        if parent.is_null() {
+            return parent;
+        }
+        let children = parent.get().children();
+        match children.iter().position(|child| *child == node_ptr) {
+            Some(0) => node_ptr = parent,
+            Some(idx) => {
+                node_ptr = children[idx - 1];
+                break;
+            }
+            None => unreachable!("a node is one of its parent's children"),
+        }
+    }
+    while !node_ptr.get().is_leaf() {
+        let children = node_ptr.get().children();
+        node_ptr = children[children.len() - 1];
+    }
+    node_ptr
+}
+
+impl<'a, K: Ord, V, const F: usize> Iterator for Iter<'a, K, V, F> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.front.next() {
+                return Some(entry);
+            }
+            match self.next_leaf() {
+                Some(leaf) => self.front = self.entries(leaf),
+                // the back end may have started on the last leaf already
+                None => return self.back.next(),
+            }
+        }
+    }
+}
+
+impl<K: Ord, V, const F: usize> DoubleEndedIterator for Iter<'_, K, V, F> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.back.next_back() {
+                return Some(entry);
+            }
+            match self.next_leaf_back() {
+                Some(leaf) => self.back = self.entries(leaf),
+                None => return self.front.next_back(),
+            }
+        }
+    }
+}
+
+pub struct Keys<'a, K, V, const F: usize = DEFAULT_FANOUT>(pub(crate) Iter<'a, K, V, F>);
+
+impl<'a, K: Ord, V, const F: usize> Iterator for Keys<'a, K, V, F> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(k, _)| k)
+    }
+}
+
+impl<K: Ord, V, const F: usize> DoubleEndedIterator for Keys<'_, K, V, F> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back().map(|(k, _)| k)
+    }
+}
+
+pub struct Values<'a, K, V, const F: usize = DEFAULT_FANOUT>(pub(crate) Iter<'a, K, V, F>);
+
+impl<'a, K: Ord, V, const F: usize> Iterator for Values<'a, K, V, F> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(_, v)| v)
+    }
+}
+
+impl<K: Ord, V, const F: usize> DoubleEndedIterator for Values<'_, K, V, F> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back().map(|(_, v)| v)
+    }
+}
+
+// Entries moved out of a consumed tree, in key order
+pub struct IntoIter<K, V>(pub(crate) vec::IntoIter<(K, V)>);
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<K, V> DoubleEndedIterator for IntoIter<K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back()
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoIter<K, V> {}
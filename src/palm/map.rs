@@ -0,0 +1,241 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::iter::{IntoIter, Iter};
+use super::node::{LeafLayout, DEFAULT_FANOUT};
+use super::notthreadsafe::NotThreadSafe;
+use super::query::{MergeOperator, Outcome, Query, Response};
+use super::snapshot::Codec;
+use super::tree::Palm;
+use super::util::SearchKey;
+use super::wal::{BatchLog, LogFile, SyncPolicy, Wal};
+use super::worker::{PalmWrapper, Partitioning};
+
+// Owns a tree together with the worker pool running batches on it.
+//   Batches need `&mut self`, so nothing can observe the tree while
+//   workers are modifying it; in between, the tree is readable through
+//   `Deref` (e.g. `map.depth()`).
+pub struct PalmMap<K, V, const F: usize = DEFAULT_FANOUT>
+where
+    K: 'static + SearchKey + Clone + std::fmt::Debug + Sync + Send,
+    V: 'static + Clone + PartialEq + std::fmt::Debug + Sync + Send,
+{
+    tree: Arc<NotThreadSafe<Palm<K, V, F>>>,
+    pool: PalmWrapper<K, V, F>,
+    wal: Option<Box<dyn BatchLog<K, V>>>,
+}
+
+impl<K, V, const F: usize> PalmMap<K, V, F>
+where
+    K: 'static + SearchKey + Clone + std::fmt::Debug + Sync + Send,
+    V: 'static + Clone + PartialEq + std::fmt::Debug + Sync + Send,
+{
+    #[must_use]
+    pub fn new(num_threads: usize) -> Self {
+        Self::with_layout(num_threads, LeafLayout::default())
+    }
+
+    #[must_use]
+    pub fn with_layout(num_threads: usize, layout: LeafLayout) -> Self {
+        let tree = Arc::new(NotThreadSafe::new(Palm::with_layout(num_threads, layout)));
+        let pool = PalmWrapper::new(tree.clone(), num_threads);
+        Self {
+            tree,
+            pool,
+            wal: None,
+        }
+    }
+
+    pub fn get(&self, k: &K) -> Option<V> {
+        self.tree.get().get(k)
+    }
+
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        self.apply_one(Query::Insertion { k, v })
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        self.apply_one(Query::Deletion { k: k.clone() })
+    }
+
+    // Returns the merged value
+    pub fn update(&mut self, k: K, op: V) -> V {
+        self.apply_one(Query::Update { k, op }).unwrap()
+    }
+
+    pub fn insert_if_absent(&mut self, k: K, v: V) -> Outcome<V> {
+        self.apply_conditional(Query::InsertIfAbsent { k, v })
+    }
+
+    pub fn compare_and_swap(&mut self, k: K, expected: V, new: V) -> Outcome<V> {
+        self.apply_conditional(Query::CompareAndSwap { k, expected, new })
+    }
+
+    // See `Palm::bulk_load` and `Palm::bulk_merge`
+    pub fn bulk_load(&mut self, iter: impl IntoIterator<Item = (K, V)>, fill_factor: f64) {
+        self.tree.get_mut().bulk_load(iter, fill_factor);
+    }
+
+    pub fn bulk_merge(&mut self, iter: impl IntoIterator<Item = (K, V)>, fill_factor: f64) {
+        self.tree.get_mut().bulk_merge(iter, fill_factor);
+    }
+
+    // See `Palm::load_snapshot`; saving goes through `Deref`
+    pub fn load_snapshot(&mut self, reader: impl io::Read) -> io::Result<()>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        self.tree.get_mut().load_snapshot(reader)
+    }
+
+    // Replays the write-ahead log at `path`, then logs every later batch to
+    //   it before applying the batch. Recovery is `load_snapshot` followed
+    //   by `open_wal`; a log holding updates needs the merge operator set
+    //   first. Returns the number of batches replayed.
+    pub fn open_wal(&mut self, path: impl AsRef<Path>, sync: SyncPolicy) -> io::Result<usize>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        let (wal, batches) = Wal::open(path, sync)?;
+        self.attach_wal(wal, batches)
+    }
+
+    // Like `open_wal`, for a log kept in something other than a plain file
+    pub fn open_wal_file(
+        &mut self,
+        file: impl LogFile + 'static,
+        sync: SyncPolicy,
+    ) -> io::Result<usize>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        let (wal, batches) = Wal::open_file(file, sync)?;
+        self.attach_wal(wal, batches)
+    }
+
+    fn attach_wal<L: LogFile + 'static>(
+        &mut self,
+        wal: Wal<K, V, L>,
+        batches: Vec<Vec<Query<K, V>>>,
+    ) -> io::Result<usize>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        // not logged again
+        self.wal = None;
+        for batch in &batches {
+            self.apply_batch(batch);
+        }
+        self.wal = Some(Box::new(wal));
+        Ok(batches.len())
+    }
+
+    // Empties the log, once a snapshot saved after the last batch is on
+    //   disk; replaying a batch twice would redo its updates
+    pub fn truncate_wal(&mut self) -> io::Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal.truncate(),
+            None => Ok(()),
+        }
+    }
+
+    // Takes effect from the next batch
+    pub fn set_partitioning(&mut self, partitioning: Partitioning) {
+        self.tree.get_mut().partitioning = partitioning;
+    }
+
+    // Used by `Query::Update`; a tree has no merge operator by default
+    pub fn set_merge_operator(&mut self, merge: impl MergeOperator<V> + 'static) {
+        self.tree.get_mut().merge = Some(Box::new(merge));
+    }
+
+    // Responses come back in the order of `queries`
+    pub fn apply_batch(&mut self, queries: &[Query<K, V>]) -> Vec<(Query<K, V>, Response<K, V>)> {
+        self.try_apply_batch(queries)
+            .expect("appending to the write-ahead log failed")
+    }
+
+    // Like `apply_batch`, but the batch is not applied if it can't be logged
+    pub fn try_apply_batch(
+        &mut self,
+        queries: &[Query<K, V>],
+    ) -> io::Result<Vec<(Query<K, V>, Response<K, V>)>> {
+        // checked up front, as a worker panicking mid-batch would stall the others
+        assert!(
+            self.tree.get().merge.is_some()
+                || !queries.iter().any(|q| matches!(q, Query::Update { .. })),
+            "updates need a merge operator"
+        );
+        if let Some(wal) = &mut self.wal {
+            wal.append(queries)?;
+        }
+        Ok(self.pool.run_batch(queries))
+    }
+
+    pub fn pool(&self) -> &PalmWrapper<K, V, F> {
+        &self.pool
+    }
+
+    fn apply_one(&mut self, query: Query<K, V>) -> Option<V> {
+        match self.apply_batch(&[query]).pop() {
+            Some((_, Response::Value(v))) => v,
+            _ => panic!("Should never be here. "),
+        }
+    }
+
+    fn apply_conditional(&mut self, query: Query<K, V>) -> Outcome<V> {
+        match self.apply_batch(&[query]).pop() {
+            Some((_, Response::Outcome(outcome))) => outcome,
+            _ => panic!("Should never be here. "),
+        }
+    }
+}
+
+impl<K, V, const F: usize> std::ops::Deref for PalmMap<K, V, F>
+where
+    K: 'static + SearchKey + Clone + std::fmt::Debug + Sync + Send,
+    V: 'static + Clone + PartialEq + std::fmt::Debug + Sync + Send,
+{
+    type Target = Palm<K, V, F>;
+
+    fn deref(&self) -> &Palm<K, V, F> {
+        self.tree.get()
+    }
+}
+
+impl<'a, K, V, const F: usize> IntoIterator for &'a PalmMap<K, V, F>
+where
+    K: 'static + SearchKey + Clone + std::fmt::Debug + Sync + Send,
+    V: 'static + Clone + PartialEq + std::fmt::Debug + Sync + Send,
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, F>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.tree.get().iter()
+    }
+}
+
+impl<K, V, const F: usize> IntoIterator for PalmMap<K, V, F>
+where
+    K: 'static + SearchKey + Clone + std::fmt::Debug + Sync + Send,
+    V: 'static + Clone + PartialEq + std::fmt::Debug + Sync + Send,
+{
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let Self { tree, pool, .. } = self;
+        // joins the workers, which hold the other references to the tree
+        drop(pool);
+        match Arc::try_unwrap(tree) {
+            Ok(tree) => tree.into_inner().into_iter(),
+            Err(_) => panic!("Should never be here. "),
+        }
+    }
+}
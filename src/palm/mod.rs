@@ -1,9 +1,24 @@
-pub mod modification;
+pub(crate) mod balance;
+// public only for the loom models
+#[cfg(feature = "loom")]
+pub mod handshake;
+#[cfg(not(feature = "loom"))]
+pub(crate) mod handshake;
+pub mod invariants;
+pub mod iter;
+pub mod map;
+pub(crate) mod modification;
 pub mod node;
-pub mod nodeptr;
-pub mod notthreadsafe;
+pub(crate) mod nodeptr;
+pub(crate) mod notthreadsafe;
 pub mod query;
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod simd;
+pub mod snapshot;
+pub mod stats;
+pub(crate) mod sync;
 pub mod tree;
 pub mod util;
 pub mod vector;
+pub mod wal;
 pub mod worker;
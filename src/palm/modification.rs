@@ -1,13 +1,10 @@
 use super::nodeptr::NodePtr;
 
 #[derive(Debug, Clone)]
-pub enum Modification<K: Ord + Clone, V: Clone> {
-    Overflow {
-        nodes: Vec<(K, NodePtr<K, V>)>,
-        orphan: Vec<(K, V)>,
-    },
-    Underflow {
-        nodes: Vec<(K, NodePtr<K, V>)>,
-        orphan: Vec<(K, V)>,
-    },
+pub enum Modification<K: Ord + Clone, V: Clone, const F: usize> {
+    Overflow { nodes: Vec<(K, NodePtr<K, V, F>)> },
+    // some child of the parent this is filed under dropped below MIN_LEN;
+    //   the parent merges or redistributes its underfull children
+    //   with their siblings
+    Underflow,
 }
@@ -1,42 +1,128 @@
 /// ported from bplustree baseline
 use super::nodeptr::NodePtr;
+#[cfg(feature = "loom")]
+use super::sync::UnsafeCell;
 use super::util::*;
 use super::vector::MyVector;
 
+use std::mem::{align_of, size_of};
 use std::ptr;
 
-pub type Vector<T> = MyVector<T>;
-const B: usize = 19;
-pub const MIN_LEN: usize = B;
-pub const MAX_LEN: usize = 2 * B;
+pub type Vector<T, const F: usize> = MyVector<T, F>;
+
+// Fanout (children of a full internal node) used unless a tree asks for
+//   another one; it keeps an `i32 -> i32` node within 8 cache lines
+pub const DEFAULT_FANOUT: usize = 38;
+
+// The largest fanout that keeps a node of `K -> V` within 8 cache lines
+//   (at least 4), e.g. `Palm::<u64, Blob, { fanout_for::<u64, Blob>() }>`
+pub const fn fanout_for<K, V>() -> usize {
+    let mut fanout = 4;
+    while node_size::<K, V>(fanout + 1) <= 512 {
+        fanout += 1;
+    }
+    fanout
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+const fn round_up(size: usize, align: usize) -> usize {
+    size.div_ceil(align) * align
+}
+
+// `MyVector<T, len>`: the length, then the array
+const fn vector_size<T>(len: usize) -> usize {
+    round_up(
+        size_of::<usize>() + len * size_of::<T>(),
+        max(align_of::<T>(), align_of::<usize>()),
+    )
+}
+
+// The size of `Node<K, V, fanout>`: the header, the keys, then the values or
+//   children behind the tag of `Elements`, which is padded to the alignment
+//   of the variant
+const fn node_size<K, V>(fanout: usize) -> usize {
+    let keys_align = max(align_of::<K>(), align_of::<usize>());
+    let vals_align = max(align_of::<V>(), align_of::<usize>());
+    let ptrs_align = align_of::<usize>();
+    let elements_align = max(vals_align, ptrs_align);
+    let elements = round_up(
+        max(
+            vals_align + vector_size::<V>(fanout),
+            ptrs_align + vector_size::<usize>(fanout),
+        ),
+        elements_align,
+    );
+    // level, parent and next
+    let keys_start = round_up(3 * size_of::<usize>(), keys_align);
+    let elements_start = round_up(keys_start + vector_size::<K>(fanout), elements_align);
+    round_up(elements_start + elements, max(keys_align, elements_align))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LeafLayout {
+    // new keys are appended, so a leaf is only sorted when it splits;
+    //   lookups have to scan the whole leaf
+    Unsorted,
+    // keys are kept in order on every insert, lookups use lower_bound
+    #[default]
+    Sorted,
+}
 
 #[derive(Debug)]
 #[repr(C)]
-pub struct Node<K, V> {
-    // 512 bytes (4 * 64 (cache line))
-    pub level: usize,          // 8 bytes
-    pub parent: NodePtr<K, V>, // 8 bytes
+pub(crate) struct Node<K, V, const F: usize = DEFAULT_FANOUT> {
+    // 504 bytes (7 * 64 (cache line) + 56) for i32 -> i32 and the default fanout
+    pub level: usize,             // 8 bytes
+    pub parent: NodePtr<K, V, F>, // 8 bytes
+    pub next: NodePtr<K, V, F>,   // 8 bytes, right sibling (leaves only)
+
+    pub keys: Vector<K, F>,      // 160 bytes = 8 (len) + 38 * 4
+    elements: Elements<K, V, F>, // 320 bytes = 8 (tag) + 8 (len) + 38 * 8
 
-    pub keys: Vector<K>,      // 168 bytes ~ (2*19 + 1) * 4 + 8 + 4 (align)
-    elements: Elements<K, V>, // 328 bytes ~ (2*19 + 1) * 8 + 8 + 8
+    // nodes are reached through raw pointers, so their accesses are
+    //   recorded here for loom (see `RawPointerOps`)
+    #[cfg(feature = "loom")]
+    pub(crate) access: UnsafeCell<()>,
 }
 
+// both variants stay inline so a node keeps its cache-line layout
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
-pub enum Elements<K, V> {
-    Vals(Vector<V>),
-    Ptrs(Vector<NodePtr<K, V>>),
+pub(crate) enum Elements<K, V, const F: usize> {
+    Vals(Vector<V, F>),
+    Ptrs(Vector<NodePtr<K, V, F>, F>),
 }
 
 use Elements::{Ptrs, Vals};
 
-impl<K, V> Node<K, V> {
+impl<K, V, const F: usize> Node<K, V, F> {
+    pub const MAX_LEN: usize = F - 1;
+    pub const MIN_LEN: usize = (F - 1) / 2;
+    // evaluated (and so checked at compile time) by the constructors
+    const VALID_FANOUT: () = assert!(F >= 4, "fanout must be at least 4");
+
     #[must_use]
-    pub fn leaf_with(keys: Vector<K>, vals: Vector<V>, parent: NodePtr<K, V>) -> Box<Self> {
+    pub fn leaf_with(
+        keys: Vector<K, F>,
+        vals: Vector<V, F>,
+        parent: NodePtr<K, V, F>,
+    ) -> Box<Self> {
+        let () = Self::VALID_FANOUT;
         Box::new(Self {
             keys,
             elements: Vals(vals),
             parent,
+            next: NodePtr::new(ptr::null_mut()),
             level: 1,
+            #[cfg(feature = "loom")]
+            access: UnsafeCell::new(()),
         })
     }
 
@@ -47,16 +133,20 @@ impl<K, V> Node<K, V> {
 
     #[must_use]
     pub fn internal_with(
-        keys: Vector<K>,
-        vals: Vector<NodePtr<K, V>>,
-        parent: NodePtr<K, V>,
+        keys: Vector<K, F>,
+        vals: Vector<NodePtr<K, V, F>, F>,
+        parent: NodePtr<K, V, F>,
         level: usize,
     ) -> Box<Self> {
+        let () = Self::VALID_FANOUT;
         Box::new(Self {
             keys,
             elements: Ptrs(vals),
             parent,
+            next: NodePtr::new(ptr::null_mut()),
             level,
+            #[cfg(feature = "loom")]
+            access: UnsafeCell::new(()),
         })
     }
 
@@ -80,65 +170,69 @@ impl<K, V> Node<K, V> {
         self.len() == 0
     }
 
-    pub fn vals(&self) -> &Vector<V> {
+    pub fn vals(&self) -> &Vector<V, F> {
         match &self.elements {
             Vals(vals) => vals,
             Ptrs(_) => panic!("Tried accessing values on an internal node"),
         }
     }
 
-    pub fn vals_mut(&mut self) -> &mut Vector<V> {
+    pub fn vals_mut(&mut self) -> &mut Vector<V, F> {
         match &mut self.elements {
             Vals(vals) => vals,
             Ptrs(_) => panic!("Tried accessing values on an internal node"),
         }
     }
 
-    pub fn ptrs(&mut self) -> &mut Vector<NodePtr<K, V>> {
+    pub fn ptrs(&mut self) -> &mut Vector<NodePtr<K, V, F>, F> {
         match &mut self.elements {
             Vals(_) => panic!("Tried accessing values on an internal node"),
             Ptrs(ptrs) => ptrs,
         }
     }
 
+    // `ptrs` for readers, which must not create a `&mut Node`
+    pub fn children(&self) -> &Vector<NodePtr<K, V, F>, F> {
+        match &self.elements {
+            Vals(_) => panic!("Tried accessing children of a leaf"),
+            Ptrs(ptrs) => ptrs,
+        }
+    }
+
     #[must_use]
     pub fn is_leaf(&self) -> bool {
         self.level == 1
     }
 
-    pub fn stat() {
-        println!("Node size: {}", std::mem::size_of::<Self>());
-        println!("- level: {}", std::mem::size_of::<usize>());
-        println!("- parent: {}", std::mem::size_of::<NodePtr<K, V>>());
-        println!("- keys: {}", std::mem::size_of::<MyVector<K>>());
-        println!("- elements: {}", std::mem::size_of::<Elements<K, V>>());
+    // Whether this is a leaf by its contents rather than by `level`
+    pub(crate) fn holds_values(&self) -> bool {
+        matches!(self.elements, Vals(_))
     }
 }
 
-impl<K: std::fmt::Debug + Ord + Clone, V: Clone> Node<K, V> {
-    pub fn search(&self, key: &K) -> Option<V> {
-        let idx = self.keys.linear_search(key);
-        if idx < self.len() {
+impl<K: std::fmt::Debug + SearchKey + Clone, V: Clone, const F: usize> Node<K, V, F> {
+    pub fn search(&self, key: &K, layout: LeafLayout, kernels: &Kernels<K>) -> Option<V> {
+        let idx = self.index_of(key, layout, kernels);
+        if self.has_exact_key_at(idx, key) {
             Some(self.vals()[idx].clone())
         } else {
             None
         }
     }
 
-    pub fn insert(&mut self, key: K, val: V) -> Option<V> {
-        assert_eq!(self.is_leaf(), true);
-        let idx = self.keys.linear_search(&key);
-        if self.has_exact_key_at(idx, &key) {
-            Some(std::mem::replace(&mut self.vals_mut()[idx], val))
-        } else {
-            self.keys.push(key);
-            self.vals_mut().push(val);
-            None
-        }
+    pub fn remove(&mut self, idx: usize) -> V {
+        assert!(self.is_leaf());
+        self.keys.remove(idx);
+        self.vals_mut().remove(idx)
     }
 
-    pub fn index_of(&self, key: &K) -> usize {
-        self.keys.linear_search(key)
+    // Returns the position of `key`, or where it would be inserted
+    //   (the end of the leaf if unsorted)
+    pub fn index_of(&self, key: &K, layout: LeafLayout, kernels: &Kernels<K>) -> usize {
+        match layout {
+            LeafLayout::Unsorted => kernels.linear_search(&self.keys, key),
+            LeafLayout::Sorted => kernels.lower_bound(&self.keys, key),
+        }
     }
 
     pub fn val_at(&self, idx: usize) -> Option<V> {
@@ -152,20 +246,9 @@ impl<K: std::fmt::Debug + Ord + Clone, V: Clone> Node<K, V> {
     pub fn has_exact_key_at(&self, idx: usize, key: &K) -> bool {
         idx < self.len() && self.keys[idx] == *key
     }
-
-    pub fn find_leaf(&mut self, key: &K) -> &mut Self {
-        if self.is_leaf() {
-            self
-        } else {
-            let idx = self.keys.upper_bound(key);
-            assert!(idx <= self.keys.len());
-            assert!(idx < self.ptrs().len());
-            self.ptrs()[idx].get_mut().find_leaf(key)
-        }
-    }
 }
 
-impl<K, V> Drop for Node<K, V> {
+impl<K, V, const F: usize> Drop for Node<K, V, F> {
     fn drop(&mut self) {
         if !self.is_leaf() {
             for child in self.ptrs() {
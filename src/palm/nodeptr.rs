@@ -1,13 +1,12 @@
 use super::node::Node;
-use super::util::*;
 use std::cmp::Ordering;
 use std::hash::{Hash, Hasher};
 use std::ptr;
 
 #[derive(Debug)]
-pub struct NodePtr<K, V>(*mut Node<K, V>);
-impl<K, V> NodePtr<K, V> {
-    pub fn new(ptr: *mut Node<K, V>) -> Self {
+pub struct NodePtr<K, V, const F: usize>(*mut Node<K, V, F>);
+impl<K, V, const F: usize> NodePtr<K, V, F> {
+    pub fn new(ptr: *mut Node<K, V, F>) -> Self {
         Self(ptr)
     }
 
@@ -18,7 +17,7 @@ impl<K, V> NodePtr<K, V> {
     }
 
     #[must_use]
-    pub fn as_ptr(self) -> *mut Node<K, V> {
+    pub fn as_ptr(self) -> *mut Node<K, V, F> {
         self.0
     }
 
@@ -28,42 +27,34 @@ impl<K, V> NodePtr<K, V> {
             self.0 = ptr::null_mut();
             let node = unsafe { Box::from_raw(raw_ptr) };
             drop(node);
-            assert_eq!(self.is_null(), true);
+            assert!(self.is_null());
         }
     }
 }
 
-impl<K, V> Clone for NodePtr<K, V> {
-    #[must_use]
+impl<K, V, const F: usize> Clone for NodePtr<K, V, F> {
     fn clone(&self) -> Self {
-        Self::new(self.0)
+        *self
     }
 }
-impl<K, V> Copy for NodePtr<K, V> {}
-unsafe impl<K, V> Send for NodePtr<K, V> {}
-unsafe impl<K, V> Sync for NodePtr<K, V> {}
+impl<K, V, const F: usize> Copy for NodePtr<K, V, F> {}
+unsafe impl<K, V, const F: usize> Send for NodePtr<K, V, F> {}
+unsafe impl<K, V, const F: usize> Sync for NodePtr<K, V, F> {}
 
-impl<K, V> Eq for NodePtr<K, V> {}
-impl<K, V> PartialEq for NodePtr<K, V> {
-    #[must_use]
+impl<K, V, const F: usize> Eq for NodePtr<K, V, F> {}
+impl<K, V, const F: usize> PartialEq for NodePtr<K, V, F> {
     fn eq(&self, other: &Self) -> bool {
         let Self(p1) = self;
         let Self(p2) = other;
-        assert_eq!(
-            ptr::eq(p1.get_mut(), p2.get_mut()),
-            *p1 as usize == *p2 as usize
-        );
-        *p1 as usize == *p2 as usize
+        std::ptr::eq(*p1, *p2)
     }
 }
-impl<K, V> PartialOrd for NodePtr<K, V> {
-    #[must_use]
+impl<K, V, const F: usize> PartialOrd for NodePtr<K, V, F> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
-impl<K, V> Ord for NodePtr<K, V> {
-    #[must_use]
+impl<K, V, const F: usize> Ord for NodePtr<K, V, F> {
     fn cmp(&self, other: &Self) -> Ordering {
         let Self(p1) = self;
         let Self(p2) = other;
@@ -71,7 +62,7 @@ impl<K, V> Ord for NodePtr<K, V> {
     }
 }
 
-impl<K, V> Hash for NodePtr<K, V> {
+impl<K, V, const F: usize> Hash for NodePtr<K, V, F> {
     fn hash<H: Hasher>(&self, state: &mut H) {
         let Self(p) = self;
         (*p as u64).hash(state);
@@ -1,5 +1,8 @@
-use std::cell::UnsafeCell;
+use super::sync::UnsafeCell;
 
+// State the workers share without locks, their accesses being ordered by the
+//   barriers and the handshake instead. Under loom each access is recorded
+//   when the reference is handed out, not for as long as it is held.
 pub struct NotThreadSafe<T> {
     data: UnsafeCell<T>,
 }
@@ -12,11 +15,15 @@ impl<T> NotThreadSafe<T> {
     }
 
     pub fn get<'a>(&self) -> &'a T {
-        unsafe { &*(self.data.get()) }
+        self.data.with(|ptr| unsafe { &*ptr })
     }
 
     pub fn get_mut<'a>(&self) -> &'a mut T {
-        unsafe { &mut *(self.data.get()) }
+        self.data.with_mut(|ptr| unsafe { &mut *ptr })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
     }
 }
 
@@ -4,19 +4,99 @@ use std::cmp::Ordering;
 pub enum Query<K, V> {
     Retrieval { k: K },
     Insertion { k: K, v: V },
+    Deletion { k: K },
+    // folds `op` into the value of `k` with the tree's `MergeOperator`,
+    //   responding with the merged value
+    Update { k: K, op: V },
+    // writes `v` only if `k` is absent
+    InsertIfAbsent { k: K, v: V },
+    // writes `new` only if `k` currently holds `expected`
+    CompareAndSwap { k: K, expected: V, new: V },
+    // all pairs in [lo, hi), at most `limit` of them; evaluated once every
+    //   write of the batch has been applied to the leaves
+    Range { lo: K, hi: K, limit: Option<usize> },
+    // the pair with the largest key <= `k` (< `k` for a predecessor), or
+    //   the smallest key >= `k` (> `k` for a successor); evaluated like a range
+    Floor { k: K },
+    Ceiling { k: K },
+    Predecessor { k: K },
+    Successor { k: K },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response<K, V> {
+    Value(Option<V>),
+    Range(Vec<(K, V)>),
+    Entry(Option<(K, V)>),
+    Outcome(Outcome<V>),
+}
+
+// Result of a conditional write. `current` is the value of the key after
+//   the query, i.e. the written value on success.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outcome<V> {
+    pub success: bool,
+    pub current: Option<V>,
+}
+
+impl<K, V> From<Option<V>> for Response<K, V> {
+    fn from(v: Option<V>) -> Self {
+        Self::Value(v)
+    }
+}
+
+// Combines an update's operand into the current value of its key, e.g.
+//   `|v: &mut u64, op: &u64| *v += op` for counters. Updates to the same key
+//   within a batch are folded in submission order.
+pub trait MergeOperator<V>: Send + Sync {
+    // the value an update folds into when its key is absent
+    fn initial(&self) -> V;
+    fn merge(&self, value: &mut V, op: &V);
+}
+
+impl<V: Default, M: Fn(&mut V, &V) + Send + Sync> MergeOperator<V> for M {
+    fn initial(&self) -> V {
+        V::default()
+    }
+
+    fn merge(&self, value: &mut V, op: &V) {
+        self(value, op)
+    }
 }
 
 impl<K: Ord + Clone, V: Clone> Query<K, V> {
     pub fn get_key(&self) -> &K {
         match self {
-            Self::Retrieval { k } | Self::Insertion { k, .. } => k,
+            Self::Retrieval { k }
+            | Self::Insertion { k, .. }
+            | Self::Deletion { k }
+            | Self::Update { k, .. }
+            | Self::InsertIfAbsent { k, .. }
+            | Self::CompareAndSwap { k, .. }
+            | Self::Floor { k }
+            | Self::Ceiling { k }
+            | Self::Predecessor { k }
+            | Self::Successor { k } => k,
+            Self::Range { lo, .. } => lo,
         }
     }
+
+    // Scans read the leaves only after the whole batch is written
+    pub fn is_scan(&self) -> bool {
+        matches!(
+            self,
+            Self::Range { .. }
+                | Self::Floor { .. }
+                | Self::Ceiling { .. }
+                | Self::Predecessor { .. }
+                | Self::Successor { .. }
+        )
+    }
 }
 
 impl<K: Ord + Clone, V: Clone> Ord for Query<K, V> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.get_key().cmp(&other.get_key())
+        self.get_key().cmp(other.get_key())
     }
 }
 impl<K: Ord + Clone, V: Clone> PartialOrd for Query<K, V> {
@@ -0,0 +1,136 @@
+// x86 search kernels. Each is a safe wrapper around a `#[target_feature]`
+//   function, so it may only be handed out (via `Kernels::with`) once the
+//   feature has been detected at runtime.
+//
+// All kernels compare a whole register of keys at once and turn the result
+//   into a byte mask; the first set (or unset) lane is the answer. x86 only
+//   has signed compares, so unsigned keys get their sign bit flipped first
+//   (which maps their order onto the signed one); equality needs no flip.
+
+macro_rules! search_kernels {
+    (
+        $feature:literal, $reg:ty, $load:ident, $movemask:ident, $xor:ident, $full:expr;
+        $($t:ty as $s:ty, $set1:ident, $cmpgt:ident, $cmpeq:ident, $flip:expr =>
+            $lower:ident, $upper:ident, $linear:ident;)*
+    ) => {
+        $(
+            pub fn $lower(keys: &[$t], value: &$t) -> usize {
+                #[target_feature(enable = $feature)]
+                unsafe fn imp(keys: &[$t], value: $t) -> usize {
+                    const LANES: usize = size_of::<$reg>() / size_of::<$t>();
+                    let rounded = (keys.len() / LANES) * LANES;
+                    let sign = $set1(<$s>::MIN);
+                    let mut needle = $set1(value as $s);
+                    if $flip {
+                        needle = $xor(needle, sign);
+                    }
+                    for i in (0..rounded).step_by(LANES) {
+                        let mut vec = $load(keys.as_ptr().add(i) as *const $reg);
+                        if $flip {
+                            vec = $xor(vec, sign);
+                        }
+                        let mask = $movemask($cmpgt(needle, vec));
+                        if mask != $full {
+                            return i + ((!mask).trailing_zeros() as usize) / size_of::<$t>();
+                        }
+                    }
+                    rounded + crate::palm::util::lower_bound(&keys[rounded..], &value)
+                }
+                unsafe { imp(keys, *value) }
+            }
+
+            pub fn $upper(keys: &[$t], value: &$t) -> usize {
+                #[target_feature(enable = $feature)]
+                unsafe fn imp(keys: &[$t], value: $t) -> usize {
+                    const LANES: usize = size_of::<$reg>() / size_of::<$t>();
+                    let rounded = (keys.len() / LANES) * LANES;
+                    let sign = $set1(<$s>::MIN);
+                    let mut needle = $set1(value as $s);
+                    if $flip {
+                        needle = $xor(needle, sign);
+                    }
+                    for i in (0..rounded).step_by(LANES) {
+                        let mut vec = $load(keys.as_ptr().add(i) as *const $reg);
+                        if $flip {
+                            vec = $xor(vec, sign);
+                        }
+                        let mask = $movemask($cmpgt(vec, needle));
+                        if mask != 0 {
+                            return i + (mask.trailing_zeros() as usize) / size_of::<$t>();
+                        }
+                    }
+                    rounded + crate::palm::util::upper_bound(&keys[rounded..], &value)
+                }
+                unsafe { imp(keys, *value) }
+            }
+
+            pub fn $linear(keys: &[$t], value: &$t) -> usize {
+                #[target_feature(enable = $feature)]
+                unsafe fn imp(keys: &[$t], value: $t) -> usize {
+                    const LANES: usize = size_of::<$reg>() / size_of::<$t>();
+                    let rounded = (keys.len() / LANES) * LANES;
+                    let needle = $set1(value as $s);
+                    for i in (0..rounded).step_by(LANES) {
+                        let vec = $load(keys.as_ptr().add(i) as *const $reg);
+                        let mask = $movemask($cmpeq(vec, needle));
+                        if mask != 0 {
+                            return i + (mask.trailing_zeros() as usize) / size_of::<$t>();
+                        }
+                    }
+                    rounded + crate::palm::util::linear_search(&keys[rounded..], &value)
+                }
+                unsafe { imp(keys, *value) }
+            }
+        )*
+    };
+}
+
+pub mod avx2 {
+    use std::arch::x86_64::*;
+    use std::mem::size_of;
+
+    search_kernels! {
+        "avx2", __m256i, _mm256_loadu_si256, _mm256_movemask_epi8, _mm256_xor_si256, -1;
+        i8 as i8, _mm256_set1_epi8, _mm256_cmpgt_epi8, _mm256_cmpeq_epi8, false =>
+            lower_bound_i8, upper_bound_i8, linear_search_i8;
+        u8 as i8, _mm256_set1_epi8, _mm256_cmpgt_epi8, _mm256_cmpeq_epi8, true =>
+            lower_bound_u8, upper_bound_u8, linear_search_u8;
+        i16 as i16, _mm256_set1_epi16, _mm256_cmpgt_epi16, _mm256_cmpeq_epi16, false =>
+            lower_bound_i16, upper_bound_i16, linear_search_i16;
+        u16 as i16, _mm256_set1_epi16, _mm256_cmpgt_epi16, _mm256_cmpeq_epi16, true =>
+            lower_bound_u16, upper_bound_u16, linear_search_u16;
+        i32 as i32, _mm256_set1_epi32, _mm256_cmpgt_epi32, _mm256_cmpeq_epi32, false =>
+            lower_bound_i32, upper_bound_i32, linear_search_i32;
+        u32 as i32, _mm256_set1_epi32, _mm256_cmpgt_epi32, _mm256_cmpeq_epi32, true =>
+            lower_bound_u32, upper_bound_u32, linear_search_u32;
+        i64 as i64, _mm256_set1_epi64x, _mm256_cmpgt_epi64, _mm256_cmpeq_epi64, false =>
+            lower_bound_i64, upper_bound_i64, linear_search_i64;
+        u64 as i64, _mm256_set1_epi64x, _mm256_cmpgt_epi64, _mm256_cmpeq_epi64, true =>
+            lower_bound_u64, upper_bound_u64, linear_search_u64;
+    }
+}
+
+pub mod sse42 {
+    use std::arch::x86_64::*;
+    use std::mem::size_of;
+
+    search_kernels! {
+        "sse4.2", __m128i, _mm_loadu_si128, _mm_movemask_epi8, _mm_xor_si128, 0xffff;
+        i8 as i8, _mm_set1_epi8, _mm_cmpgt_epi8, _mm_cmpeq_epi8, false =>
+            lower_bound_i8, upper_bound_i8, linear_search_i8;
+        u8 as i8, _mm_set1_epi8, _mm_cmpgt_epi8, _mm_cmpeq_epi8, true =>
+            lower_bound_u8, upper_bound_u8, linear_search_u8;
+        i16 as i16, _mm_set1_epi16, _mm_cmpgt_epi16, _mm_cmpeq_epi16, false =>
+            lower_bound_i16, upper_bound_i16, linear_search_i16;
+        u16 as i16, _mm_set1_epi16, _mm_cmpgt_epi16, _mm_cmpeq_epi16, true =>
+            lower_bound_u16, upper_bound_u16, linear_search_u16;
+        i32 as i32, _mm_set1_epi32, _mm_cmpgt_epi32, _mm_cmpeq_epi32, false =>
+            lower_bound_i32, upper_bound_i32, linear_search_i32;
+        u32 as i32, _mm_set1_epi32, _mm_cmpgt_epi32, _mm_cmpeq_epi32, true =>
+            lower_bound_u32, upper_bound_u32, linear_search_u32;
+        i64 as i64, _mm_set1_epi64x, _mm_cmpgt_epi64, _mm_cmpeq_epi64, false =>
+            lower_bound_i64, upper_bound_i64, linear_search_i64;
+        u64 as i64, _mm_set1_epi64x, _mm_cmpgt_epi64, _mm_cmpeq_epi64, true =>
+            lower_bound_u64, upper_bound_u64, linear_search_u64;
+    }
+}
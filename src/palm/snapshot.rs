@@ -0,0 +1,246 @@
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use super::tree::Palm;
+use super::util::SearchKey;
+
+// Snapshot layout, integers little-endian:
+//   magic, version: u32, fanout: u32, key tag, value tag, count: u64,
+//   depth: u32, `count` sorted pairs, CRC-32 of everything before it: u32
+//   Tags are u32-length-prefixed strings, see `Codec::TAG`.
+const MAGIC: &[u8; 8] = b"PALMSNAP";
+const VERSION: u32 = 1;
+// restored leaves keep some room, so the first inserts don't all split
+const FILL_FACTOR: f64 = 0.75;
+
+// Byte encoding of keys and values on disk. `TAG` names the encoding, so
+//   that a file is never read back as some other type.
+pub trait Codec: Sized {
+    const TAG: &'static str;
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self>;
+}
+
+macro_rules! int_codec {
+    ($($t:ty),*) => {
+        $(
+            impl Codec for $t {
+                const TAG: &'static str = stringify!($t);
+
+                fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+                    writer.write_all(&self.to_le_bytes())
+                }
+
+                fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+                    let mut buf = [0; std::mem::size_of::<$t>()];
+                    reader.read_exact(&mut buf)?;
+                    Ok(<$t>::from_le_bytes(buf))
+                }
+            }
+        )*
+    };
+}
+
+int_codec!(i8, u8, i16, u16, i32, u32, i64, u64, i128, u128);
+
+impl Codec for Vec<u8> {
+    const TAG: &'static str = "bytes";
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (self.len() as u64).encode(writer)?;
+        writer.write_all(self)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let len = u64::decode(reader)?;
+        // not preallocated, a corrupted length must not exhaust memory
+        let mut buf = Vec::new();
+        reader.take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(buf)
+    }
+}
+
+impl Codec for String {
+    const TAG: &'static str = "string";
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (self.len() as u64).encode(writer)?;
+        writer.write_all(self.as_bytes())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        String::from_utf8(Vec::decode(reader)?).map_err(|_| invalid("string is not UTF-8"))
+    }
+}
+
+pub(crate) fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// CRC-32 (IEEE), as in zlib
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+pub(crate) fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut c = !crc;
+    for b in bytes {
+        c = CRC_TABLE[((c ^ *b as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+// Checksums everything written through it
+pub(crate) struct CrcWriter<W> {
+    inner: W,
+    pub(crate) crc: u32,
+}
+
+impl<W: Write> CrcWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self { inner, crc: 0 }
+    }
+
+    pub(crate) fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = crc32(self.crc, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Checksums everything read through it
+pub(crate) struct CrcReader<R> {
+    inner: R,
+    pub(crate) crc: u32,
+}
+
+impl<R: Read> CrcReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self { inner, crc: 0 }
+    }
+
+    pub(crate) fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc = crc32(self.crc, &buf[..n]);
+        Ok(n)
+    }
+}
+
+pub(crate) fn write_tag<W: Write>(writer: &mut W, tag: &str) -> io::Result<()> {
+    (tag.len() as u32).encode(writer)?;
+    writer.write_all(tag.as_bytes())
+}
+
+// `file` names the kind of file in the error
+pub(crate) fn check_tag<R: Read>(reader: &mut R, file: &str, tag: &str) -> io::Result<()> {
+    let len = u32::decode(reader)?;
+    let mut found = Vec::new();
+    reader.take(len as u64).read_to_end(&mut found)?;
+    if found != tag.as_bytes() {
+        return Err(invalid(&format!(
+            "{} holds {}, not {}",
+            file,
+            String::from_utf8_lossy(&found),
+            tag
+        )));
+    }
+    Ok(())
+}
+
+impl<K, V, const F: usize> Palm<K, V, F>
+where
+    K: 'static + SearchKey + Clone + std::fmt::Debug + Codec + Send,
+    V: 'static + Clone + PartialEq + std::fmt::Debug + Codec + Send,
+{
+    // Streams the pairs in key order; only valid while no batch is running
+    pub fn save_snapshot<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = CrcWriter::new(BufWriter::new(writer));
+        writer.write_all(MAGIC)?;
+        VERSION.encode(&mut writer)?;
+        (F as u32).encode(&mut writer)?;
+        write_tag(&mut writer, K::TAG)?;
+        write_tag(&mut writer, V::TAG)?;
+        (self.iter().count() as u64).encode(&mut writer)?;
+        (self.depth as u32).encode(&mut writer)?;
+        for (k, v) in self.iter() {
+            k.encode(&mut writer)?;
+            v.encode(&mut writer)?;
+        }
+        let crc = writer.crc;
+        let mut writer = writer.into_inner();
+        crc.encode(&mut writer)?;
+        writer.flush()
+    }
+
+    // Replaces the contents of the tree with a snapshot. The tree is built
+    //   bottom-up like `bulk_load`, so its shape only depends on the pairs
+    //   and on `F`; the fanout and depth stored in the header are those of
+    //   the saved tree, and are not checked.
+    pub fn load_snapshot<R: Read>(&mut self, reader: R) -> io::Result<()> {
+        let mut reader = CrcReader::new(BufReader::new(reader));
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+        if u32::decode(&mut reader)? != VERSION {
+            return Err(invalid("unsupported snapshot version"));
+        }
+        let _fanout = u32::decode(&mut reader)?;
+        check_tag(&mut reader, "snapshot", K::TAG)?;
+        check_tag(&mut reader, "snapshot", V::TAG)?;
+        let count = u64::decode(&mut reader)?;
+        let _depth = u32::decode(&mut reader)?;
+
+        let mut entries: Vec<(K, V)> = Vec::new();
+        for _ in 0..count {
+            let k = K::decode(&mut reader)?;
+            let v = V::decode(&mut reader)?;
+            if entries.last().is_some_and(|(last, _)| *last >= k) {
+                return Err(invalid("snapshot keys are out of order"));
+            }
+            entries.push((k, v));
+        }
+        let crc = reader.crc;
+        if u32::decode(&mut reader.into_inner())? != crc {
+            return Err(invalid("snapshot checksum mismatch"));
+        }
+        self.build(entries, FILL_FACTOR);
+        Ok(())
+    }
+}
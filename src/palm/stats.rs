@@ -0,0 +1,102 @@
+use std::mem::size_of;
+
+use super::node::Node;
+use super::tree::Palm;
+use super::util::{RawPointerOps, SearchKey};
+
+// buckets of the fill histogram, each covering a tenth of `max_len`
+pub const FILL_BUCKETS: usize = 10;
+
+// Shape of a tree between batches, see `Palm::stats`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    pub depth: usize,
+    pub keys: usize,
+    // bounds on the keys of a node, the root excepted
+    pub min_len: usize,
+    pub max_len: usize,
+    // leaves first, i.e. `levels[0]` describes level 1
+    pub levels: Vec<LevelStats>,
+    pub node_size: usize,
+    // `node_size` per node; memory owned by the keys and values themselves
+    //   (e.g. the bytes of a `Vec<u8>` key) is not included
+    pub bytes: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LevelStats {
+    pub nodes: usize,
+    pub keys: usize,
+    // keys per node
+    pub min_len: usize,
+    pub avg_len: f64,
+    pub max_len: usize,
+    // nodes by `len / max_len`; full nodes go to the last bucket
+    pub fill: [usize; FILL_BUCKETS],
+    // nodes below `min_len`, which only the root may be
+    pub underfull: usize,
+}
+
+impl Stats {
+    pub fn leaves(&self) -> usize {
+        self.levels[0].nodes
+    }
+
+    pub fn internal_nodes(&self) -> usize {
+        self.levels[1..].iter().map(|l| l.nodes).sum()
+    }
+}
+
+impl<K, V, const F: usize> Palm<K, V, F>
+where
+    K: 'static + SearchKey + Clone + std::fmt::Debug,
+    V: 'static + Clone + PartialEq + std::fmt::Debug,
+{
+    // Walks the whole tree; only valid while no batch is running
+    pub fn stats(&self) -> Stats {
+        let (min_len, max_len) = (Node::<K, V, F>::MIN_LEN, Node::<K, V, F>::MAX_LEN);
+        let mut levels = Vec::new();
+        let mut nodes = vec![self.root];
+        while !nodes.is_empty() {
+            let mut level = LevelStats {
+                nodes: nodes.len(),
+                keys: 0,
+                min_len: usize::MAX,
+                avg_len: 0.0,
+                max_len: 0,
+                fill: [0; FILL_BUCKETS],
+                underfull: 0,
+            };
+            let mut children = Vec::new();
+            for node in nodes {
+                let node = node.get();
+                let len = node.len();
+                level.keys += len;
+                level.min_len = level.min_len.min(len);
+                level.max_len = level.max_len.max(len);
+                level.fill[(len * FILL_BUCKETS / max_len).min(FILL_BUCKETS - 1)] += 1;
+                if len < min_len {
+                    level.underfull += 1;
+                }
+                if !node.is_leaf() {
+                    children.extend_from_slice(node.children());
+                }
+            }
+            level.avg_len = level.keys as f64 / level.nodes as f64;
+            levels.push(level);
+            nodes = children;
+        }
+        levels.reverse();
+
+        let node_count: usize = levels.iter().map(|l| l.nodes).sum();
+        Stats {
+            depth: self.depth,
+            keys: levels[0].keys,
+            min_len,
+            max_len,
+            levels,
+            node_size: size_of::<Node<K, V, F>>(),
+            bytes: node_count * size_of::<Node<K, V, F>>(),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+// The synchronization the workers use, swapped for loom's model-checked
+//   versions by the `loom` feature (see tests/loom.rs). Bulk loading keeps
+//   using std's scoped threads, which loom can't model.
+#[cfg(not(feature = "loom"))]
+pub(crate) use std::{
+    hint,
+    sync::{atomic, mpsc, Barrier, Condvar, Mutex},
+    thread,
+};
+
+#[cfg(feature = "loom")]
+pub(crate) use loom::{
+    hint,
+    sync::{atomic, Condvar, Mutex},
+    thread,
+};
+
+#[cfg(feature = "loom")]
+pub(crate) use loom::cell::UnsafeCell;
+
+// std's cell behind loom's interface, where every access goes through
+//   `with` or `with_mut` so that loom can record it
+#[cfg(not(feature = "loom"))]
+#[derive(Debug)]
+pub(crate) struct UnsafeCell<T>(std::cell::UnsafeCell<T>);
+
+#[cfg(not(feature = "loom"))]
+impl<T> UnsafeCell<T> {
+    pub(crate) fn new(data: T) -> Self {
+        Self(std::cell::UnsafeCell::new(data))
+    }
+
+    #[inline(always)]
+    pub(crate) fn with<R>(&self, f: impl FnOnce(*const T) -> R) -> R {
+        f(self.0.get())
+    }
+
+    #[inline(always)]
+    pub(crate) fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+        f(self.0.get())
+    }
+
+    pub(crate) fn into_inner(self) -> T {
+        self.0.into_inner()
+    }
+}
+
+// loom has no barrier of its own
+#[cfg(feature = "loom")]
+pub(crate) struct Barrier {
+    num_threads: usize,
+    // threads waiting, and how many times the barrier has opened
+    state: Mutex<(usize, usize)>,
+    opened: Condvar,
+}
+
+#[cfg(feature = "loom")]
+impl Barrier {
+    pub(crate) fn new(num_threads: usize) -> Self {
+        Self {
+            num_threads,
+            state: Mutex::new((0, 0)),
+            opened: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn wait(&self) {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.1;
+        state.0 += 1;
+        if state.0 == self.num_threads {
+            *state = (0, generation + 1);
+            self.opened.notify_all();
+        } else {
+            while state.1 == generation {
+                state = self.opened.wait(state).unwrap();
+            }
+        }
+    }
+}
+
+// loom's channel loses track of messages when a receiver blocks, so the
+//   workers' channels are modelled with a queue instead
+#[cfg(feature = "loom")]
+pub(crate) mod mpsc {
+    use super::{Condvar, Mutex};
+    use std::collections::VecDeque;
+    use std::sync::mpsc::{RecvError, SendError};
+    use std::sync::Arc;
+
+    struct Channel<T> {
+        queue: Mutex<VecDeque<T>>,
+        sent: Condvar,
+    }
+
+    pub(crate) struct Sender<T>(Arc<Channel<T>>);
+    pub(crate) struct Receiver<T>(Arc<Channel<T>>);
+
+    pub(crate) fn channel<T>() -> (Sender<T>, Receiver<T>) {
+        let channel = Arc::new(Channel {
+            queue: Mutex::new(VecDeque::new()),
+            sent: Condvar::new(),
+        });
+        (Sender(channel.clone()), Receiver(channel))
+    }
+
+    impl<T> Sender<T> {
+        pub(crate) fn send(&self, msg: T) -> Result<(), SendError<T>> {
+            self.0.queue.lock().unwrap().push_back(msg);
+            self.0.sent.notify_one();
+            Ok(())
+        }
+    }
+
+    impl<T> Receiver<T> {
+        pub(crate) fn recv(&self) -> Result<T, RecvError> {
+            let mut queue = self.0.queue.lock().unwrap();
+            loop {
+                if let Some(msg) = queue.pop_front() {
+                    return Ok(msg);
+                }
+                queue = self.0.sent.wait(queue).unwrap();
+            }
+        }
+    }
+}
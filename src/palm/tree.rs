@@ -1,69 +1,350 @@
 use std::collections::HashMap;
 use std::collections::VecDeque;
 use std::mem;
-use std::sync::{Arc, Barrier};
-use std::thread;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
 
+use super::iter::{IntoIter, Iter, Keys, Values};
 use super::modification::Modification as Modif;
-use super::node::{Node, MAX_LEN, MIN_LEN};
+use super::node::{LeafLayout, Node, DEFAULT_FANOUT};
 use super::nodeptr::NodePtr;
 use super::notthreadsafe::NotThreadSafe;
-use super::query::Query;
+use super::query::{MergeOperator, Outcome, Query, Response};
 use super::util::*;
-use super::worker::Worker;
+use super::worker::Partitioning;
 
 const Q: usize = 64;
 
 pub type MapType<K, V> = HashMap<K, V>;
-pub type WorkMap<K, V, T> = MapType<NodePtr<K, V>, Vec<T>>;
-pub type ModifMap<K, V> = VecDeque<(NodePtr<K, V>, Vec<Modif<K, V>>)>;
-pub type QueryMap<K, V> = VecDeque<(NodePtr<K, V>, Vec<Query<K, V>>)>;
-enum Elements<K, V> {
+// The nodes of a thread's layer with the work pending on each. A neighbour
+//   may take the work of the first node while the owner goes on with the
+//   others, so each node's work sits in a cell of its own.
+pub(crate) type Layer<K, V, T, const F: usize> =
+    VecDeque<(NodePtr<K, V, F>, NotThreadSafe<Vec<T>>)>;
+pub(crate) type ModifMap<K, V, const F: usize> = Layer<K, V, Modif<K, V, F>, F>;
+// queries are tagged with their position in the caller's batch, so that
+//   responses can be handed back in submission order
+pub type Tagged<K, V> = (usize, Query<K, V>);
+pub type TaggedResponse<K, V> = (usize, Query<K, V>, Response<K, V>);
+pub(crate) type QueryMap<K, V, const F: usize> = Layer<K, V, Tagged<K, V>, F>;
+enum Elements<K, V, const F: usize> {
     Vals(Vec<V>),
-    Ptrs(Vec<NodePtr<K, V>>),
+    Ptrs(Vec<NodePtr<K, V, F>>),
 }
 
 #[allow(non_snake_case)]
-pub struct Palm<K, V>
+pub struct Palm<K, V, const F: usize = DEFAULT_FANOUT>
 where
     K: Clone,
     V: Clone,
 {
-    pub depth: usize,
-    pub root: NodePtr<K, V>,
-    pub num_threads: usize,
+    pub(crate) depth: usize,
+    pub(crate) root: NodePtr<K, V, F>,
+    pub(crate) num_threads: usize,
+    pub(crate) layout: LeafLayout,
+    pub(crate) partitioning: Partitioning,
+    pub(crate) kernels: Kernels<K>,
+    pub(crate) merge: Option<Box<dyn MergeOperator<V>>>,
 }
 
-unsafe impl<K: Clone, V: Clone> Sync for Palm<K, V> {}
-unsafe impl<K: Clone, V: Clone> Send for Palm<K, V> {}
+// Nodes are reached through raw pointers, so the tree owns its keys and
+//   values as far as threads are concerned
+unsafe impl<K: Clone + Send + Sync, V: Clone + Send + Sync, const F: usize> Sync for Palm<K, V, F> {}
+unsafe impl<K: Clone + Send, V: Clone + Send, const F: usize> Send for Palm<K, V, F> {}
 
-impl<K, V> Palm<K, V>
+impl<K, V, const F: usize> Palm<K, V, F>
 where
-    K: 'static + Ord + Clone + std::fmt::Debug,
-    V: 'static + Clone + std::fmt::Debug,
+    K: 'static + SearchKey + Clone + std::fmt::Debug,
+    V: 'static + Clone + PartialEq + std::fmt::Debug,
 {
+    const MAX_LEN: usize = Node::<K, V, F>::MAX_LEN;
+    const MIN_LEN: usize = Node::<K, V, F>::MIN_LEN;
+
     #[must_use]
     #[allow(non_snake_case)]
     pub fn new(num_threads: usize) -> Self {
-        Node::<K, V>::stat();
+        Self::with_layout(num_threads, LeafLayout::default())
+    }
+
+    #[must_use]
+    pub fn with_layout(num_threads: usize, layout: LeafLayout) -> Self {
         Self {
             depth: 1,
-            root: NodePtr::new(Box::into_raw(Node::<K, V>::leaf())),
+            root: NodePtr::new(Box::into_raw(Node::<K, V, F>::leaf())),
             num_threads,
+            layout,
+            partitioning: Partitioning::default(),
+            kernels: K::kernels(SimdLevel::detect()),
+            merge: None,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.num_threads
+    }
+
+    pub fn layout(&self) -> LeafLayout {
+        self.layout
+    }
+
+    pub fn partitioning(&self) -> Partitioning {
+        self.partitioning
+    }
+
+    pub fn fanout(&self) -> usize {
+        F
+    }
+
+    pub fn simd_level(&self) -> SimdLevel {
+        self.kernels.level()
+    }
+
+    // Sequential point lookup, only valid while no batch is running
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.find_leaf(key)
+            .get()
+            .search(key, self.layout, &self.kernels)
+    }
+
+    // Iterators below walk the tree in key order, and like `get` are only
+    //   valid while no batch is running
+    pub fn iter(&self) -> Iter<'_, K, V, F> {
+        self.range(..)
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Iter<'_, K, V, F> {
+        let first = match range.start_bound() {
+            Bound::Included(k) | Bound::Excluded(k) => self.find_leaf(k),
+            Bound::Unbounded => self.first_leaf(),
+        };
+        let last = match range.end_bound() {
+            Bound::Included(k) | Bound::Excluded(k) => self.find_leaf(k),
+            Bound::Unbounded => self.last_leaf(),
+        };
+        Iter::new(
+            first,
+            last,
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+        )
+    }
+
+    pub fn keys(&self) -> Keys<'_, K, V, F> {
+        Keys(self.iter())
+    }
+
+    pub fn values(&self) -> Values<'_, K, V, F> {
+        Values(self.iter())
+    }
+
+    // Builds the tree bottom-up from pairs in strictly increasing key
+    //   order, the tree has to be empty. Nodes are filled to about
+    //   `fill_factor` of their capacity, leaving room for later inserts.
+    pub fn bulk_load<I>(&mut self, iter: I, fill_factor: f64)
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Send,
+        V: Send,
+    {
+        assert!(
+            self.root.get().is_leaf() && self.root.get().is_empty(),
+            "bulk_load needs an empty tree"
+        );
+        let entries = Self::collect_sorted(iter);
+        self.build(entries, fill_factor);
+    }
+
+    // Merges pairs in strictly increasing key order into the tree, values
+    //   in `iter` replacing those already present. The whole tree is
+    //   rebuilt, so small runs are cheaper to apply as a batch.
+    pub fn bulk_merge<I>(&mut self, iter: I, fill_factor: f64)
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Send,
+        V: Send,
+    {
+        let run = Self::collect_sorted(iter);
+        let old = self.drain_entries();
+        let mut entries = Vec::with_capacity(old.len() + run.len());
+        let mut old = old.into_iter().peekable();
+        for (k, v) in run {
+            while let Some((old_k, old_v)) = old.next_if(|(old_k, _)| *old_k <= k) {
+                if old_k < k {
+                    entries.push((old_k, old_v));
+                }
+            }
+            entries.push((k, v));
+        }
+        entries.extend(old);
+        self.build(entries, fill_factor);
+    }
+
+    fn collect_sorted<I: IntoIterator<Item = (K, V)>>(iter: I) -> Vec<(K, V)> {
+        let entries: Vec<_> = iter.into_iter().collect();
+        assert!(
+            entries.windows(2).all(|w| w[0].0 < w[1].0),
+            "keys must be strictly increasing"
+        );
+        entries
+    }
+
+    // Moves every pair out in key order, leaving an empty tree
+    fn drain_entries(&mut self) -> Vec<(K, V)> {
+        let mut entries = Vec::new();
+        let mut leaf = self.first_leaf();
+        while !leaf.is_null() {
+            let node = leaf.get_mut();
+            let keys: Vec<K> = mem::take(&mut node.keys).into();
+            let vals: Vec<V> = mem::take(node.vals_mut()).into();
+            let start = entries.len();
+            entries.extend(keys.into_iter().zip(vals));
+            entries[start..].sort_by(|a, b| a.0.cmp(&b.0));
+            leaf = node.next;
+        }
+        self.root.manually_drop();
+        self.root = NodePtr::new(Box::into_raw(Node::leaf()));
+        self.depth = 1;
+        entries
+    }
+
+    pub(crate) fn build(&mut self, entries: Vec<(K, V)>, fill_factor: f64)
+    where
+        K: Send,
+        V: Send,
+    {
+        assert!(fill_factor > 0.0 && fill_factor <= 1.0);
+        let fill = ((Self::MAX_LEN as f64 * fill_factor).round() as usize)
+            .clamp(Self::MIN_LEN.max(1), Self::MAX_LEN);
+        let num_threads = self.num_threads;
+
+        self.root.manually_drop();
+        if entries.is_empty() {
+            self.root = NodePtr::new(Box::into_raw(Node::leaf()));
+            self.depth = 1;
+            return;
+        }
+
+        // every node is kept along with the first and last key below it,
+        //   which the separators of the level above are made from
+        let count = Self::node_count(entries.len(), fill, Self::MIN_LEN);
+        let mut level = build_level(entries, count, num_threads, |run: Vec<(K, V)>| {
+            let (keys, vals): (Vec<K>, Vec<V>) = run.into_iter().unzip();
+            let (first, last) = (keys[0].clone(), keys[keys.len() - 1].clone());
+            let null = NodePtr::new(std::ptr::null_mut());
+            let leaf = Node::leaf_with(keys.into(), vals.into(), null);
+            (NodePtr::new(Box::into_raw(leaf)), first, last)
+        });
+        for pair in level.windows(2) {
+            pair[0].0.get_mut().next = pair[1].0;
+        }
+
+        let mut depth = 1;
+        while level.len() > 1 {
+            depth += 1;
+            let count = Self::node_count(level.len(), fill + 1, Self::MIN_LEN + 1);
+            level = build_level(
+                level,
+                count,
+                num_threads,
+                |run: Vec<(NodePtr<K, V, F>, K, K)>| {
+                    let keys: Vec<K> = run
+                        .windows(2)
+                        .map(|pair| K::separator(&pair[0].2, &pair[1].1))
+                        .collect();
+                    let (first, last) = (run[0].1.clone(), run[run.len() - 1].2.clone());
+                    let ptrs: Vec<_> = run.into_iter().map(|(child, _, _)| child).collect();
+                    let null = NodePtr::new(std::ptr::null_mut());
+                    let node = NodePtr::new(Box::into_raw(Node::internal_with(
+                        keys.into(),
+                        ptrs.into(),
+                        null,
+                        depth,
+                    )));
+                    for child in node.get_mut().ptrs() {
+                        child.get_mut().parent = node;
+                    }
+                    (node, first, last)
+                },
+            );
+        }
+        self.root = level[0].0;
+        self.depth = depth;
+    }
+
+    // Number of nodes to spread `len` entries over: about `target` in
+    //   each, but never fewer than `min`, which also keeps them below
+    //   `2 * min`
+    fn node_count(len: usize, target: usize, min: usize) -> usize {
+        let count = len.div_ceil(target);
+        if len / count < min {
+            (len / min).max(1)
+        } else {
+            count
         }
     }
 
-    pub fn partition<T: Clone>(batch: &[T], t: usize) -> Vec<Vec<T>> {
-        batch
-            .chunks((batch.len() + t - 1) / t)
-            .map(|x| x.to_vec())
+    fn first_leaf(&self) -> NodePtr<K, V, F> {
+        let mut node_ptr = self.root;
+        while !node_ptr.get().is_leaf() {
+            node_ptr = node_ptr.get().children()[0];
+        }
+        node_ptr
+    }
+
+    fn last_leaf(&self) -> NodePtr<K, V, F> {
+        let mut node_ptr = self.root;
+        while !node_ptr.get().is_leaf() {
+            let children = node_ptr.get().children();
+            node_ptr = children[children.len() - 1];
+        }
+        node_ptr
+    }
+
+    // The leaf `key` belongs in. Only shared references are taken on the
+    //   way, as readers may share the tree.
+    fn find_leaf(&self, key: &K) -> NodePtr<K, V, F> {
+        let mut node_ptr = self.root;
+        while !node_ptr.get().is_leaf() {
+            let node = node_ptr.get();
+            node_ptr = node.children()[self.kernels.upper_bound(&node.keys, key)];
+        }
+        node_ptr
+    }
+
+    // Tags each query with its position and hands out exactly t contiguous
+    //   chunks (possibly empty), as every thread has to take part in the
+    //   synchronization
+    pub(crate) fn tag(queries: &[Query<K, V>], t: usize) -> Vec<Vec<Tagged<K, V>>> {
+        let size = queries.len().div_ceil(t);
+        (0..t)
+            .map(|i| {
+                let lo = (i * size).min(queries.len());
+                let hi = ((i + 1) * size).min(queries.len());
+                (lo..hi).map(|id| (id, queries[id].clone())).collect()
+            })
             .collect()
     }
 
-    pub fn search(
-        queries: &mut Vec<Query<K, V>>,
-        curr_query: &NotThreadSafe<QueryMap<K, V>>,
-        root: NodePtr<K, V>,
+    pub(crate) fn untag(
+        len: usize,
+        responses: impl Iterator<Item = TaggedResponse<K, V>>,
+    ) -> Vec<(Query<K, V>, Response<K, V>)> {
+        let mut results: Vec<Option<_>> = (0..len).map(|_| None).collect();
+        for (id, query, response) in responses {
+            results[id] = Some((query, response));
+        }
+        results.into_iter().map(|r| r.unwrap()).collect()
+    }
+
+    pub(crate) fn search(
+        queries: &mut Vec<Tagged<K, V>>,
+        curr_query: &NotThreadSafe<QueryMap<K, V, F>>,
+        root: NodePtr<K, V, F>,
+        kernels: &Kernels<K>,
     ) {
         // Latency Hiding:
         //   1. Use BFS instead of DFS for better locality
@@ -76,28 +357,18 @@ where
         let mut paths = vec![root; queries.len()];
         let mut base = 0;
         for chunk in queries.chunks(Q) {
-            for level in (1..root.get_mut().level).rev() {
+            for level in (1..root.get().level).rev() {
                 for (i, query) in chunk.iter().enumerate() {
-                    unsafe {
-                        // prefetch sibling
-                        if base + i + 1 < paths.len() {
-                            std::intrinsics::prefetch_read_data(
-                                paths[base + i + 1].as_ptr() as *const _,
-                                2,
-                            );
-                        }
+                    // prefetch sibling
+                    if base + i + 1 < paths.len() {
+                        prefetch(paths[base + i + 1].as_ptr());
                     }
-                    let node = paths[base + i].get_mut();
-                    let idx = node.keys.upper_bound(&query.get_key());
-                    paths[base + i] = node.ptrs()[idx];
-                    unsafe {
-                        if level != 1 {
-                            // prefetch child
-                            std::intrinsics::prefetch_read_data(
-                                paths[base + i].as_ptr() as *const _,
-                                2,
-                            );
-                        }
+                    let node = paths[base + i].get();
+                    let idx = kernels.upper_bound(&node.keys, query.1.get_key());
+                    paths[base + i] = node.children()[idx];
+                    if level != 1 {
+                        // prefetch child
+                        prefetch(paths[base + i].as_ptr());
                     }
                 }
             }
@@ -109,13 +380,13 @@ where
         let mut idx = 0;
         for (i, query) in queries.drain(..).enumerate() {
             if idx > 0 && query_guard[idx - 1].0 == paths[i] {
-                query_guard[idx - 1].1.push(query);
+                query_guard[idx - 1].1.get_mut().push(query);
             } else {
                 if idx < query_guard.len() {
                     query_guard[idx].0 = paths[i];
-                    query_guard[idx].1.push(query);
+                    query_guard[idx].1.get_mut().push(query);
                 } else {
-                    query_guard.push_back((paths[i], vec![query]));
+                    query_guard.push_back((paths[i], NotThreadSafe::new(vec![query])));
                 }
                 idx += 1;
             }
@@ -123,14 +394,15 @@ where
         query_guard.truncate(idx);
     }
 
-    pub fn redistribute_work<T: std::fmt::Debug + Clone>(
+    pub(crate) fn redistribute_work<T: std::fmt::Debug + Clone>(
         thread_index: usize,
-        input: &[NotThreadSafe<VecDeque<(NodePtr<K, V>, Vec<T>)>>],
+        input: &[NotThreadSafe<Layer<K, V, T, F>>],
         _num_threads: usize,
-        their_last: &mut NodePtr<K, V>,
+        their_last: &mut NodePtr<K, V, F>,
     ) {
-        // initialize
-        let curr_layer = input[thread_index].get_mut();
+        // initialize; other threads may be reading the layers, so only the
+        //   work in their nodes' cells is moved
+        let curr_layer = input[thread_index].get();
         if curr_layer.is_empty() {
             return;
         }
@@ -140,7 +412,7 @@ where
             for map in input.iter().take(thread_index).rev() {
                 if let Some((node, _)) = map.get().back() {
                     if *node == curr_layer[0].0 {
-                        std::mem::replace(their_last, *node);
+                        *their_last = *node;
                     }
                     break;
                 }
@@ -159,9 +431,14 @@ where
             //   (recall that if there is only one element in the queue, a thread
             //     would use next thread's first element as its "their_first")
             for map in input.iter().skip(thread_index + 1) {
-                if let Some((node, modif)) = map.get_mut().front_mut() {
+                if let Some((node, modif)) = map.get().front() {
                     if *node == curr_layer.back().unwrap().0 {
-                        curr_layer.back_mut().unwrap().1.extend(modif.drain(..));
+                        curr_layer
+                            .back()
+                            .unwrap()
+                            .1
+                            .get_mut()
+                            .append(modif.get_mut());
                     } else {
                         break;
                     }
@@ -172,18 +449,18 @@ where
 
     #[allow(non_snake_case)]
     fn big_split(
-        node: &mut Node<K, V>,
+        node: &mut Node<K, V, F>,
         keys: &mut Vec<K>,
-        vals: &mut Elements<K, V>,
-    ) -> Vec<(K, NodePtr<K, V>)> {
+        vals: &mut Elements<K, V, F>,
+    ) -> Vec<(K, NodePtr<K, V, F>)> {
         let mut splits = Vec::new();
-        while keys.len() > MAX_LEN {
+        while keys.len() > Self::MAX_LEN {
             let len = keys.len();
             match vals {
                 Elements::Ptrs(ptrs) => {
                     let new_node = NodePtr::new(Box::into_raw(Node::internal_with(
-                        keys.split_off(len - MIN_LEN).into(),
-                        ptrs.split_off(len - MIN_LEN).into(),
+                        keys.split_off(len - Self::MIN_LEN).into(),
+                        ptrs.split_off(len - Self::MIN_LEN).into(),
                         node.parent,
                         node.level,
                     )));
@@ -195,11 +472,15 @@ where
                 }
                 Elements::Vals(vals) => {
                     let new_node = NodePtr::new(Box::into_raw(Node::leaf_with(
-                        keys.split_off(len - MIN_LEN).into(),
-                        vals.split_off(len - MIN_LEN).into(),
+                        keys.split_off(len - Self::MIN_LEN).into(),
+                        vals.split_off(len - Self::MIN_LEN).into(),
                         node.parent,
                     )));
-                    let new_key = new_node.get().keys[0].clone();
+                    // new leaves are split off from the right, so each one
+                    //   links to the previously created one
+                    new_node.get_mut().next = node.next;
+                    node.next = new_node;
+                    let new_key = K::separator(keys.last().unwrap(), &new_node.get().keys[0]);
                     splits.push((new_key, new_node));
                 }
             }
@@ -210,11 +491,11 @@ where
 
     #[allow(non_snake_case)]
     fn maybe_split(
-        node: &mut Node<K, V>,
+        node: &mut Node<K, V, F>,
         keys: &mut Vec<K>,
-        vals: &mut Elements<K, V>,
-    ) -> Option<Vec<(K, NodePtr<K, V>)>> {
-        let ret = if keys.len() > MAX_LEN {
+        vals: &mut Elements<K, V, F>,
+    ) -> Option<Vec<(K, NodePtr<K, V, F>)>> {
+        let ret = if keys.len() > Self::MAX_LEN {
             Some(Self::big_split(node, keys, vals))
         } else {
             None
@@ -239,7 +520,7 @@ where
         // As keys of queries is non-decreasing, we only need to
         //   compare with the last element
         if Some(key) == keys.last() {
-            vals.last().map(|v| v.clone())
+            vals.last().cloned()
         } else {
             None
         }
@@ -255,103 +536,518 @@ where
         }
     }
 
+    fn try_update(
+        keys: &mut Vec<K>,
+        vals: &mut Vec<V>,
+        key: &K,
+        op: &V,
+        merge: &dyn MergeOperator<V>,
+    ) -> V {
+        if Some(key) != keys.last() {
+            keys.push(key.clone());
+            vals.push(merge.initial());
+        }
+        let v = vals.last_mut().unwrap();
+        merge.merge(v, op);
+        v.clone()
+    }
+
+    fn try_remove(keys: &mut Vec<K>, vals: &mut Vec<V>, key: &K) -> Option<V> {
+        if Some(key) == keys.last() {
+            keys.pop();
+            vals.pop()
+        } else {
+            None
+        }
+    }
+
+    fn merge_into_buffer(node: &Node<K, V, F>, keys: &mut Vec<K>, vals: &mut Vec<V>) {
+        // both the leaf and the buffered new keys are sorted, and disjoint
+        let mut new_keys = Vec::with_capacity(node.len() + keys.len());
+        let mut new_vals = Vec::with_capacity(node.len() + vals.len());
+        {
+            let mut buffered = keys.drain(..).zip(vals.drain(..)).peekable();
+            for (k, v) in node.keys.iter().zip(node.vals().iter()) {
+                while buffered.peek().is_some_and(|(bk, _)| bk < k) {
+                    let (bk, bv) = buffered.next().unwrap();
+                    new_keys.push(bk);
+                    new_vals.push(bv);
+                }
+                new_keys.push(k.clone());
+                new_vals.push(v.clone());
+            }
+            for (bk, bv) in buffered {
+                new_keys.push(bk);
+                new_vals.push(bv);
+            }
+        }
+        std::mem::swap(&mut new_keys, keys);
+        std::mem::swap(&mut new_vals, vals);
+    }
+
+    fn push_modif(
+        next_map: &mut ModifMap<K, V, F>,
+        parent: NodePtr<K, V, F>,
+        modif: Modif<K, V, F>,
+    ) {
+        if !next_map.is_empty() && next_map.back().unwrap().0 == parent {
+            next_map.back().unwrap().1.get_mut().push(modif);
+        } else {
+            next_map.push_back((parent, NotThreadSafe::new(vec![modif])));
+        }
+    }
+
+    fn merge_or_redistribute(
+        keys: &mut Vec<K>,
+        ptrs: &mut Vec<NodePtr<K, V, F>>,
+        idx: usize,
+    ) -> bool {
+        // merges ptrs[idx + 1] into ptrs[idx] if both fit in a single node,
+        //   otherwise splits their entries evenly between the two
+        // Note the left node always survives a merge, so the first child of
+        //   a node is never freed while other threads may be looking at it
+        let left = ptrs[idx].get_mut();
+        let mut right_ptr = ptrs[idx + 1];
+        let right = right_ptr.get_mut();
+        if left.is_leaf() {
+            let mut pairs: Vec<(K, V)> = Vec::with_capacity(left.len() + right.len());
+            pairs.extend(left.keys.iter().cloned().zip(left.vals().iter().cloned()));
+            pairs.extend(right.keys.iter().cloned().zip(right.vals().iter().cloned()));
+            pairs.sort_by(|a, b| a.0.cmp(&b.0));
+            let (mut new_keys, mut new_vals): (Vec<K>, Vec<V>) = pairs.drain(..).unzip();
+            if new_keys.len() <= Self::MAX_LEN {
+                left.keys.clone_from_vec(&mut new_keys);
+                left.vals_mut().clone_from_vec(&mut new_vals);
+                left.next = right.next;
+                right.keys.clear();
+                right.vals_mut().clear();
+                keys.remove(idx);
+                ptrs.remove(idx + 1);
+                right_ptr.manually_drop();
+                true
+            } else {
+                let mid = new_keys.len() / 2;
+                let mut right_keys = new_keys.split_off(mid);
+                let mut right_vals = new_vals.split_off(mid);
+                keys[idx] = K::separator(new_keys.last().unwrap(), &right_keys[0]);
+                left.keys.clone_from_vec(&mut new_keys);
+                left.vals_mut().clone_from_vec(&mut new_vals);
+                right.keys.clone_from_vec(&mut right_keys);
+                right.vals_mut().clone_from_vec(&mut right_vals);
+                false
+            }
+        } else {
+            let mut new_keys: Vec<K> = left.keys.to_vec();
+            new_keys.push(keys[idx].clone());
+            new_keys.extend(right.keys.iter().cloned());
+            let mut new_ptrs: Vec<NodePtr<K, V, F>> = left.ptrs().to_vec();
+            new_ptrs.extend(right.ptrs().iter().cloned());
+            // a node with a single child cannot fix that child by itself,
+            //   so once it gains siblings here it has to be rebalanced
+            Self::rebalance_children(&mut new_keys, &mut new_ptrs);
+            if new_keys.len() <= Self::MAX_LEN {
+                for child in new_ptrs.iter() {
+                    child.get_mut().parent = ptrs[idx];
+                }
+                left.keys.clone_from_vec(&mut new_keys);
+                left.ptrs().clone_from_vec(&mut new_ptrs);
+                // detach the children first, otherwise they are freed with `right`
+                right.keys.clear();
+                right.ptrs().clear();
+                keys.remove(idx);
+                ptrs.remove(idx + 1);
+                right_ptr.manually_drop();
+                true
+            } else {
+                let mid = new_keys.len() / 2;
+                let mut right_keys = new_keys.split_off(mid + 1);
+                let mut right_ptrs = new_ptrs.split_off(mid + 1);
+                keys[idx] = new_keys.pop().unwrap();
+                for child in new_ptrs.iter() {
+                    child.get_mut().parent = ptrs[idx];
+                }
+                for child in right_ptrs.iter() {
+                    child.get_mut().parent = right_ptr;
+                }
+                left.keys.clone_from_vec(&mut new_keys);
+                left.ptrs().clone_from_vec(&mut new_ptrs);
+                right.keys.clone_from_vec(&mut right_keys);
+                right.ptrs().clone_from_vec(&mut right_ptrs);
+                false
+            }
+        }
+    }
+
+    fn rebalance_children(keys: &mut Vec<K>, ptrs: &mut Vec<NodePtr<K, V, F>>) {
+        // Children are only touched by the thread owning their parent, and
+        //   point-to-point sync guarantees every thread that modified them
+        //   at the previous level is done by now
+        let mut idx = 0;
+        while idx < ptrs.len() && ptrs.len() > 1 {
+            if ptrs[idx].get().len() >= Self::MIN_LEN {
+                idx += 1;
+                continue;
+            }
+            // pair with the right sibling if there is one, else the left one
+            let left = if idx + 1 < ptrs.len() { idx } else { idx - 1 };
+            if Self::merge_or_redistribute(keys, ptrs, left) {
+                // the merged node may still be underfull
+                idx = left;
+            } else {
+                idx = left + 1;
+            }
+        }
+    }
+
+    fn is_underfull(node: &Node<K, V, F>) -> bool {
+        if node.parent.is_null() {
+            // the root may hold any number of keys, but an internal root
+            //   with a single child is collapsed
+            !node.is_leaf() && node.is_empty()
+        } else {
+            node.len() < Self::MIN_LEN
+        }
+    }
+
     #[allow(non_snake_case)]
-    pub fn apply_to_leaf_nodes(
-        curr_query: &NotThreadSafe<QueryMap<K, V>>,
-        next_modif: &NotThreadSafe<ModifMap<K, V>>,
-        their_last: NodePtr<K, V>,
-    ) -> Vec<(Query<K, V>, Option<V>)> {
-        let mut results: Vec<(Query<K, V>, Option<V>)> = Vec::new();
-        let curr_map = curr_query.get_mut();
+    pub(crate) fn apply_to_leaf_nodes(
+        curr_query: &NotThreadSafe<QueryMap<K, V, F>>,
+        next_modif: &NotThreadSafe<ModifMap<K, V, F>>,
+        their_last: NodePtr<K, V, F>,
+        ranges: &mut Vec<(usize, NodePtr<K, V, F>)>,
+        layout: LeafLayout,
+        kernels: &Kernels<K>,
+        merge: Option<&dyn MergeOperator<V>>,
+    ) -> Vec<TaggedResponse<K, V>> {
+        let mut results: Vec<TaggedResponse<K, V>> = Vec::new();
+        ranges.clear();
         let next_map = next_modif.get_mut();
         next_map.clear();
+        // a null `their_last` matches no leaf
+        let leaves = curr_query
+            .get()
+            .iter()
+            .filter(|(node_ptr, _)| *node_ptr != their_last);
+        Self::apply_to_leaves(
+            leaves,
+            next_map,
+            &mut results,
+            ranges,
+            layout,
+            kernels,
+            merge,
+        );
+        results
+    }
 
+    // Applies the queries of a run of leaves, appending to `results`,
+    //   `ranges` and `next_map`
+    pub(crate) fn apply_to_leaves<'a>(
+        leaves: impl Iterator<Item = &'a (NodePtr<K, V, F>, NotThreadSafe<Vec<Tagged<K, V>>>)>,
+        next_map: &mut ModifMap<K, V, F>,
+        results: &mut Vec<TaggedResponse<K, V>>,
+        ranges: &mut Vec<(usize, NodePtr<K, V, F>)>,
+        layout: LeafLayout,
+        kernels: &Kernels<K>,
+        merge: Option<&dyn MergeOperator<V>>,
+    ) {
         // buffer
         let mut keys: Vec<K> = Vec::new();
         let mut vals: Vec<V> = Vec::new();
-        for (i, (node_ptr, queries)) in curr_map.iter_mut().enumerate() {
-            unsafe {
-                if i + 1 < curr_query.get_mut().len() {
-                    std::intrinsics::prefetch_write_data(curr_query.get_mut()[i + 1].0.as_ptr(), 2);
-                }
+        let mut leaves = leaves.peekable();
+        while let Some((node_ptr, queries)) = leaves.next() {
+            if let Some((next, _)) = leaves.peek() {
+                prefetch(next.as_ptr());
             }
 
             let node = node_ptr.get_mut();
-            if !their_last.is_null() && *node_ptr == their_last {
-                continue;
-            }
-
             keys.clear();
             vals.clear();
 
-            for query in queries.drain(..) {
-                let idx = node.index_of(query.get_key());
+            for (id, query) in queries.get_mut().drain(..) {
+                let idx = node.index_of(query.get_key(), layout, kernels);
                 let result = match &query {
-                    Query::Retrieval { k } => node
-                        .val_at(idx)
-                        .or_else(|| Self::try_lookup(&keys, &vals, k)),
-                    Query::Insertion { k, v } => node
-                        .val_at(idx)
-                        .and_then(|_| Some(std::mem::replace(&mut node.vals_mut()[idx], v.clone())))
-                        .or_else(|| {
-                            Self::try_insert(&mut keys, &mut vals, k.clone(), v.clone()).or(None)
-                        }),
+                    Query::Retrieval { k } => {
+                        if node.has_exact_key_at(idx, k) {
+                            node.val_at(idx)
+                        } else {
+                            Self::try_lookup(&keys, &vals, k)
+                        }
+                    }
+                    Query::Insertion { k, v } => {
+                        if node.has_exact_key_at(idx, k) {
+                            Some(std::mem::replace(&mut node.vals_mut()[idx], v.clone()))
+                        } else {
+                            Self::try_insert(&mut keys, &mut vals, k.clone(), v.clone())
+                        }
+                    }
+                    Query::Deletion { k } => {
+                        if node.has_exact_key_at(idx, k) {
+                            Some(node.remove(idx))
+                        } else {
+                            Self::try_remove(&mut keys, &mut vals, k)
+                        }
+                    }
+                    Query::Update { k, op } => {
+                        let merge = merge.expect("updates need a merge operator");
+                        if node.has_exact_key_at(idx, k) {
+                            let v = &mut node.vals_mut()[idx];
+                            merge.merge(v, op);
+                            Some(v.clone())
+                        } else {
+                            Some(Self::try_update(&mut keys, &mut vals, k, op, merge))
+                        }
+                    }
+                    Query::InsertIfAbsent { k, v } => {
+                        let current = if node.has_exact_key_at(idx, k) {
+                            node.val_at(idx)
+                        } else {
+                            Self::try_lookup(&keys, &vals, k)
+                        };
+                        let outcome = match current {
+                            Some(current) => Outcome {
+                                success: false,
+                                current: Some(current),
+                            },
+                            None => {
+                                Self::try_insert(&mut keys, &mut vals, k.clone(), v.clone());
+                                Outcome {
+                                    success: true,
+                                    current: Some(v.clone()),
+                                }
+                            }
+                        };
+                        results.push((id, query, Response::Outcome(outcome)));
+                        continue;
+                    }
+                    Query::CompareAndSwap { k, expected, new } => {
+                        let current = if node.has_exact_key_at(idx, k) {
+                            Some(&mut node.vals_mut()[idx])
+                        } else if Some(k) == keys.last() {
+                            vals.last_mut()
+                        } else {
+                            None
+                        };
+                        let outcome = match current {
+                            Some(current) if current == expected => {
+                                *current = new.clone();
+                                Outcome {
+                                    success: true,
+                                    current: Some(new.clone()),
+                                }
+                            }
+                            current => Outcome {
+                                success: false,
+                                current: current.cloned(),
+                            },
+                        };
+                        results.push((id, query, Response::Outcome(outcome)));
+                        continue;
+                    }
+                    _ => {
+                        // scans are filled in by `scan_ranges` once all
+                        //   leaves are written
+                        ranges.push((results.len(), *node_ptr));
+                        results.push((id, query, Response::Value(None)));
+                        continue;
+                    }
                 };
-                results.push((query, result));
+                results.push((id, query, result.into()));
             }
 
-            if node.len() + keys.len() <= MAX_LEN {
+            if node.len() + keys.len() <= Self::MAX_LEN {
                 for (k, v) in keys.drain(..).zip(vals.drain(..)) {
-                    node.keys.push(k);
-                    node.vals_mut().push(v);
+                    // buffered keys are known to be absent from the leaf
+                    let idx = match layout {
+                        LeafLayout::Unsorted => node.len(),
+                        LeafLayout::Sorted => kernels.lower_bound(&node.keys, &k),
+                    };
+                    node.keys.insert(idx, k);
+                    node.vals_mut().insert(idx, v);
                 }
             } else {
-                keys.extend(node.keys.iter().map(|k| k.clone()));
-                vals.extend(node.vals().iter().map(|v| v.clone()));
-                let mut indices: Vec<_> = (0..keys.len()).collect();
-                indices.sort_by_key(|i| keys[*i].clone());
-                let mut new_keys = Vec::with_capacity(keys.len());
-                let mut new_vals = Vec::with_capacity(vals.len());
-                for idx in indices {
-                    new_keys.push(keys[idx].clone());
-                    new_vals.push(vals[idx].clone());
+                if layout == LeafLayout::Sorted {
+                    Self::merge_into_buffer(node, &mut keys, &mut vals);
+                } else {
+                    Self::sort_into_buffer(node, &mut keys, &mut vals);
                 }
-                std::mem::swap(&mut new_keys, &mut keys);
-                std::mem::swap(&mut new_vals, &mut vals);
 
                 let mut temp_vals = Elements::Vals(vals);
                 if let Some(nodes) = Self::maybe_split(node, &mut keys, &mut temp_vals) {
-                    let modif = Modif::Overflow {
-                        nodes,
-                        orphan: Vec::new(),
-                    };
-                    if !next_map.is_empty() && next_map.back().unwrap().0 == node.parent {
-                        next_map.back_mut().unwrap().1.push(modif);
-                    } else {
-                        next_map.push_back((node.parent, vec![modif]));
-                    }
+                    let modif = Modif::Overflow { nodes };
+                    Self::push_modif(next_map, node.parent, modif);
                 }
                 vals = match temp_vals {
                     Elements::Vals(temp) => temp,
                     _ => panic!("Should never be here. "),
                 }
             }
+
+            if Self::is_underfull(node) {
+                Self::push_modif(next_map, node.parent, Modif::Underflow);
+            }
+        }
+    }
+
+    fn sort_into_buffer(node: &Node<K, V, F>, keys: &mut Vec<K>, vals: &mut Vec<V>) {
+        keys.extend(node.keys.iter().cloned());
+        vals.extend(node.vals().iter().cloned());
+        let mut indices: Vec<_> = (0..keys.len()).collect();
+        indices.sort_by_key(|i| keys[*i].clone());
+        let mut new_keys = Vec::with_capacity(keys.len());
+        let mut new_vals = Vec::with_capacity(vals.len());
+        for idx in indices {
+            new_keys.push(keys[idx].clone());
+            new_vals.push(vals[idx].clone());
+        }
+        std::mem::swap(&mut new_keys, keys);
+        std::mem::swap(&mut new_vals, vals);
+    }
+
+    fn scan_range(mut leaf: NodePtr<K, V, F>, lo: &K, hi: &K, limit: Option<usize>) -> Vec<(K, V)> {
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut found = Vec::new();
+        while !leaf.is_null() && found.len() < limit {
+            let node = leaf.get();
+            let mut past_hi = false;
+            let start = found.len();
+            for (k, v) in node.keys.iter().zip(node.vals().iter()) {
+                if k >= hi {
+                    past_hi = true;
+                } else if k >= lo {
+                    found.push((k.clone(), v.clone()));
+                }
+            }
+            // unsorted leaves are unordered internally, but keys never
+            //   decrease from one leaf to the next
+            found[start..].sort_by(|a, b| a.0.cmp(&b.0));
+            if past_hi {
+                break;
+            }
+            leaf = node.next;
+        }
+        found.truncate(limit);
+        found
+    }
+
+    // Smallest pair at or after `k` (strictly after it if `strict`),
+    //   starting from the leaf `k` was routed to
+    fn scan_ceiling(mut leaf: NodePtr<K, V, F>, k: &K, strict: bool) -> Option<(K, V)> {
+        while !leaf.is_null() {
+            let node = leaf.get();
+            let found = node
+                .keys
+                .iter()
+                .zip(node.vals().iter())
+                .filter(|(key, _)| *key > k || (!strict && *key == k))
+                .min_by(|a, b| a.0.cmp(b.0));
+            if let Some((key, v)) = found {
+                return Some((key.clone(), v.clone()));
+            }
+            leaf = node.next;
+        }
+        None
+    }
+
+    // Largest pair at or before `k` (strictly before it if `strict`).
+    //   Leaves only link to their right sibling, so earlier leaves are
+    //   reached through the path from the root instead. Internal nodes are
+    //   not modified before the scans are done, but leaves split off in this
+    //   batch are only reachable from the leaf they were split from.
+    fn scan_floor(
+        root: NodePtr<K, V, F>,
+        k: &K,
+        strict: bool,
+        kernels: &Kernels<K>,
+    ) -> Option<(K, V)> {
+        let mut path = Vec::new();
+        let mut node_ptr = root;
+        while !node_ptr.get().is_leaf() {
+            let idx = kernels.upper_bound(&node_ptr.get().keys, k);
+            path.push((node_ptr, idx));
+            node_ptr = node_ptr.get_mut().ptrs()[idx];
+        }
+        let mut stop = NodePtr::new(std::ptr::null_mut());
+        loop {
+            // the leaf and the leaves split off from it, up to the leaf
+            //   visited before
+            let mut best: Option<(&K, &V)> = None;
+            let mut leaf = node_ptr;
+            while !leaf.is_null() && leaf != stop {
+                let node = leaf.get();
+                let mut past_k = false;
+                for (key, v) in node.keys.iter().zip(node.vals().iter()) {
+                    if key < k || (!strict && key == k) {
+                        if best.is_none_or(|(b, _)| key > b) {
+                            best = Some((key, v));
+                        }
+                    } else {
+                        past_k = true;
+                    }
+                }
+                // keys never decrease from one leaf to the next
+                if past_k {
+                    break;
+                }
+                leaf = node.next;
+            }
+            if let Some((key, v)) = best {
+                return Some((key.clone(), v.clone()));
+            }
+
+            // step to the closest leaf on the left
+            stop = node_ptr;
+            loop {
+                let (parent, idx) = path.pop()?;
+                if idx > 0 {
+                    path.push((parent, idx - 1));
+                    node_ptr = parent.get_mut().ptrs()[idx - 1];
+                    break;
+                }
+            }
+            while !node_ptr.get().is_leaf() {
+                let last = node_ptr.get_mut().ptrs().len() - 1;
+                path.push((node_ptr, last));
+                node_ptr = node_ptr.get_mut().ptrs()[last];
+            }
         }
-        results
     }
 
-    pub fn apply_to_internal_nodes(
-        curr_modif: &NotThreadSafe<ModifMap<K, V>>,
-        next_modif: &NotThreadSafe<ModifMap<K, V>>,
-        their_last: NodePtr<K, V>,
+    pub(crate) fn scan_ranges(
+        results: &mut [TaggedResponse<K, V>],
+        ranges: &[(usize, NodePtr<K, V, F>)],
+        root: NodePtr<K, V, F>,
+        kernels: &Kernels<K>,
+    ) {
+        for (idx, leaf) in ranges {
+            let (_, query, response) = &mut results[*idx];
+            *response = match query {
+                Query::Range { lo, hi, limit } => {
+                    Response::Range(Self::scan_range(*leaf, lo, hi, *limit))
+                }
+                Query::Floor { k } => Response::Entry(Self::scan_floor(root, k, false, kernels)),
+                Query::Predecessor { k } => {
+                    Response::Entry(Self::scan_floor(root, k, true, kernels))
+                }
+                Query::Ceiling { k } => Response::Entry(Self::scan_ceiling(*leaf, k, false)),
+                Query::Successor { k } => Response::Entry(Self::scan_ceiling(*leaf, k, true)),
+                _ => panic!("Should never be here. "),
+            };
+        }
+    }
+
+    pub(crate) fn apply_to_internal_nodes(
+        curr_modif: &NotThreadSafe<ModifMap<K, V, F>>,
+        next_modif: &NotThreadSafe<ModifMap<K, V, F>>,
+        their_last: NodePtr<K, V, F>,
     ) {
         let next_map = next_modif.get_mut();
         next_map.clear();
 
         let mut keys: Vec<_> = Vec::new();
         let mut ptrs: Vec<_> = Vec::new();
-        for (node_ptr, modifs) in curr_modif.get_mut() {
-            assert_eq!(node_ptr.is_null(), false);
+        for (node_ptr, modifs) in curr_modif.get() {
+            assert!(!node_ptr.is_null());
             if !their_last.is_null() && *node_ptr == their_last {
                 continue;
             }
@@ -362,30 +1058,31 @@ where
             ptrs.clear();
             ptrs.extend(node.ptrs().clone().to_vec());
 
-            for modif in modifs {
+            let mut underflow = false;
+            for modif in modifs.get_mut().iter() {
                 match modif {
                     Modif::Overflow { nodes, .. } => {
                         for (k, child) in nodes.iter() {
-                            let idx = keys.lower_bound(&k);
+                            let idx = keys.lower_bound(k);
                             keys.insert(idx, k.clone());
                             ptrs.insert(idx + 1, *child);
                         }
                     }
-                    _ => {}
+                    Modif::Underflow => underflow = true,
                 }
             }
+            // new siblings from splits are inserted first, so that underfull
+            //   children can also borrow from them
+            if underflow {
+                Self::rebalance_children(&mut keys, &mut ptrs);
+            }
 
             let mut temp_ptrs = Elements::Ptrs(ptrs);
             if let Some(nodes) = Self::maybe_split(node, &mut keys, &mut temp_ptrs) {
-                let modif = Modif::Overflow {
-                    nodes,
-                    orphan: Vec::new(),
-                };
-                if !next_map.is_empty() && next_map.back().unwrap().0 == node.parent {
-                    next_map.back_mut().unwrap().1.push(modif);
-                } else {
-                    next_map.push_back((node.parent, vec![modif]));
-                }
+                let modif = Modif::Overflow { nodes };
+                Self::push_modif(next_map, node.parent, modif);
+            } else if Self::is_underfull(node) {
+                Self::push_modif(next_map, node.parent, Modif::Underflow);
             }
             ptrs = match temp_ptrs {
                 Elements::Ptrs(temp) => temp,
@@ -395,16 +1092,35 @@ where
     }
 
     #[allow(non_snake_case)]
-    pub fn handle_root(
+    pub(crate) fn handle_root(
         tree_ptr: &Arc<NotThreadSafe<Self>>,
-        modifs_list: &[NotThreadSafe<ModifMap<K, V>>],
+        modifs_list: &[NotThreadSafe<ModifMap<K, V, F>>],
     ) {
         // collect all the modifs
         let mut collected = Vec::new();
+        let mut underflow = false;
         for modifs in modifs_list {
-            for (node_ptr, mut modifs) in modifs.get_mut().drain(..) {
-                assert_eq!(node_ptr.is_null(), true);
-                collected.extend(modifs.drain(..));
+            for (node_ptr, modifs) in modifs.get_mut().drain(..) {
+                assert!(node_ptr.is_null());
+                for modif in modifs.into_inner() {
+                    match modif {
+                        Modif::Overflow { .. } => collected.push(modif),
+                        Modif::Underflow => underflow = true,
+                    }
+                }
+            }
+        }
+
+        if underflow {
+            // collapse internal roots left with a single child
+            let tree = tree_ptr.get_mut();
+            while !tree.root.get().is_leaf() && tree.root.get().is_empty() {
+                let mut old_root = tree.root;
+                let child = old_root.get_mut().ptrs().pop().unwrap();
+                child.get_mut().parent = NodePtr::new(std::ptr::null_mut());
+                tree.root = child;
+                tree.depth -= 1;
+                old_root.manually_drop();
             }
         }
 
@@ -420,103 +1136,103 @@ where
             assert!(!tree.root.get_mut().ptrs().is_empty());
 
             let node = tree.root.get_mut();
-            let mut keys: Vec<_> = mem::replace(&mut node.keys, unsafe {
-                mem::MaybeUninit::uninit().assume_init()
-            })
-            .into();
-            let mut ptrs: Vec<_> = mem::replace(node.ptrs(), unsafe {
-                mem::MaybeUninit::uninit().assume_init()
-            })
-            .into();
+            let mut keys: Vec<_> = mem::take(&mut node.keys).into();
+            let mut ptrs: Vec<_> = mem::take(node.ptrs()).into();
 
             let mut work = Vec::new();
             std::mem::swap(&mut collected, &mut work);
             for modif in work.drain(..) {
-                match modif {
-                    Modif::Overflow { mut nodes, .. } => {
-                        for (k, child) in nodes.drain(..) {
-                            assert_eq!(child.get_mut().parent.is_null(), true);
-                            let idx = keys.lower_bound(&k);
-                            keys.insert(idx, k);
-                            ptrs.insert(idx + 1, child);
-                            child.get_mut().parent = tree.root;
-                        }
+                if let Modif::Overflow { mut nodes, .. } = modif {
+                    for (k, child) in nodes.drain(..) {
+                        assert!(child.get_mut().parent.is_null());
+                        let idx = keys.lower_bound(&k);
+                        keys.insert(idx, k);
+                        ptrs.insert(idx + 1, child);
+                        child.get_mut().parent = tree.root;
                     }
-                    _ => {}
                 }
             }
 
             if let Some(nodes) = Self::maybe_split(node, &mut keys, &mut Elements::Ptrs(ptrs)) {
-                collected.push(Modif::Overflow {
-                    nodes,
-                    orphan: Vec::new(),
-                });
+                collected.push(Modif::Overflow { nodes });
             }
         }
     }
+}
 
-    pub fn run_batch(
-        tree: &Arc<NotThreadSafe<Self>>,
-        queries: &mut Vec<Query<K, V>>,
-    ) -> Vec<(Query<K, V>, Option<V>)>
-    where
-        K: Ord + std::fmt::Debug + Clone + Send + Sync + 'static,
-        V: Clone + std::fmt::Debug + Send + Sync + 'static,
-    {
-        // by sorting in advance, redistribution can be significantly simplied
-        //   note that sort has to be stable to preserve the order of queries
-        queries.sort();
-
-        let num_threads = tree.get().num_threads;
-        let mut chunks = Self::partition(&queries, num_threads);
-        let barrier = Arc::new(Barrier::new(num_threads));
-        let q_query: Arc<Vec<_>> = Arc::new(
-            (0..2)
-                .map(|_| {
-                    (0..num_threads)
-                        .map(|_| NotThreadSafe::new(VecDeque::new()))
-                        .collect()
-                })
-                .collect(),
-        );
-        let q_modif: Arc<Vec<_>> = Arc::new(
-            (0..2)
-                .map(|_| {
-                    (0..num_threads)
-                        .map(|_| NotThreadSafe::new(VecDeque::new()))
-                        .collect()
+// Splits `items` into `count` runs whose lengths differ by at most one and
+//   makes a node of each run, every thread taking a contiguous group of runs
+fn build_level<T: Send, N>(
+    mut items: Vec<T>,
+    count: usize,
+    num_threads: usize,
+    make: impl Fn(Vec<T>) -> N + Sync,
+) -> Vec<N> {
+    let len = items.len();
+    let run_start = |run: usize| run * len / count;
+    let num_threads = num_threads.clamp(1, count);
+    let group_start = |t: usize| t * count / num_threads;
+
+    let mut groups = Vec::with_capacity(num_threads);
+    for t in (0..num_threads).rev() {
+        groups.push(items.split_off(run_start(group_start(t))));
+    }
+    groups.reverse();
+
+    let make = &make;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = groups
+            .into_iter()
+            .enumerate()
+            .map(|(t, mut group)| {
+                scope.spawn(move || {
+                    let (first, end) = (group_start(t), group_start(t + 1));
+                    let mut nodes = Vec::with_capacity(end - first);
+                    for run in (first..end).rev() {
+                        let items = group.split_off(run_start(run) - run_start(first));
+                        nodes.push(make(items));
+                    }
+                    nodes.reverse();
+                    // node pointers are not `Send`, but each belongs to this
+                    //   thread only until it is joined
+                    NotThreadSafe::new(nodes)
                 })
-                .collect(),
-        );
-        let first: Arc<NotThreadSafe<Vec<_>>> = Arc::new(NotThreadSafe::new(
-            (0..num_threads).map(|_| Vec::new()).collect(),
-        ));
-        let last: Arc<NotThreadSafe<Vec<_>>> = Arc::new(NotThreadSafe::new(
-            (0..num_threads).map(|_| Vec::new()).collect(),
-        ));
-        let mut handles = vec![];
-        for (thread_idx, chunk) in chunks.drain(..).enumerate() {
-            let p_barrier = barrier.clone();
-            let p_tree = tree.clone();
-            let p_q = q_query.clone();
-            let p_m = q_modif.clone();
-            let p_f = first.clone();
-            let p_l = last.clone();
-            handles.push(thread::spawn(move || {
-                let worker = Worker::new(thread_idx, p_tree.clone(), p_barrier, p_m, p_q, p_f, p_l);
-                worker.execute(chunk)
-            }));
-        }
-
-        let mut result = vec![];
-        for handle in handles {
-            result.extend(handle.join().unwrap());
-        }
-        result
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap().into_inner())
+            .collect()
+    })
+}
+
+impl<'a, K, V, const F: usize> IntoIterator for &'a Palm<K, V, F>
+where
+    K: 'static + SearchKey + Clone + std::fmt::Debug,
+    V: 'static + Clone + PartialEq + std::fmt::Debug,
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, F>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K, V, const F: usize> IntoIterator for Palm<K, V, F>
+where
+    K: 'static + SearchKey + Clone + std::fmt::Debug,
+    V: 'static + Clone + PartialEq + std::fmt::Debug,
+{
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(mut self) -> Self::IntoIter {
+        IntoIter(self.drain_entries().into_iter())
     }
 }
 
-impl<K: Clone, V: Clone> Drop for Palm<K, V> {
+impl<K: Clone, V: Clone, const F: usize> Drop for Palm<K, V, F> {
     fn drop(&mut self) {
         self.root.manually_drop();
     }
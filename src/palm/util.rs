@@ -2,7 +2,7 @@
 use super::node::Node;
 use super::nodeptr::NodePtr;
 
-pub trait RawPointerOps {
+pub(crate) trait RawPointerOps {
     type Output;
 
     fn get<'a>(self) -> &'a Self::Output;
@@ -21,174 +21,368 @@ impl<T> RawPointerOps for *mut T {
     }
 }
 
-impl<K, V> RawPointerOps for NodePtr<K, V> {
-    type Output = Node<K, V>;
+impl<K, V, const F: usize> RawPointerOps for NodePtr<K, V, F> {
+    type Output = Node<K, V, F>;
 
-    #[must_use]
     fn get<'a>(self) -> &'a Self::Output {
-        unsafe {
-            let Self(ptr) = self;
-            &*ptr
-        }
+        let node = unsafe { &*self.as_ptr() };
+        #[cfg(feature = "loom")]
+        node.access.with(|_| ());
+        node
     }
 
-    #[must_use]
     fn get_mut<'a>(self) -> &'a mut Self::Output {
-        unsafe {
-            let Self(ptr) = self;
-            &mut *ptr
-        }
+        let node = unsafe { &mut *self.as_ptr() };
+        #[cfg(feature = "loom")]
+        node.access.with_mut(|_| ());
+        node
     }
 }
 
-pub trait SortedSearch<T> {
-    fn lower_bound(&self, value: &T) -> usize;
-    fn upper_bound(&self, value: &T) -> usize;
+// Hints the CPU to pull `ptr` into cache, a no-op where unsupported
+#[inline(always)]
+pub fn prefetch<T>(ptr: *const T) {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    unsafe {
+        #[cfg(target_arch = "x86")]
+        use std::arch::x86::{_mm_prefetch, _MM_HINT_T1};
+        #[cfg(target_arch = "x86_64")]
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T1};
+        _mm_prefetch::<_MM_HINT_T1>(ptr as *const i8);
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let _ = ptr;
 }
 
-impl<T: Ord> SortedSearch<T> for [T] {
-    default fn lower_bound(&self, value: &T) -> usize {
-        // invariants: [0, l) < value & value <= [r, len)
-        unsafe {
-            std::intrinsics::prefetch_read_data(&self, 2);
-        }
-        let mut l = 0;
-        let mut r = self.len();
-        while l < r {
-            let mid = (l + r) / 2;
-            if self[mid] < *value {
-                l = mid + 1;
-            } else {
-                r = mid;
-            }
+pub fn lower_bound<T: Ord>(keys: &[T], value: &T) -> usize {
+    // invariants: [0, l) < value & value <= [r, len)
+    prefetch(keys.as_ptr());
+    let mut l = 0;
+    let mut r = keys.len();
+    while l < r {
+        let mid = (l + r) / 2;
+        if keys[mid] < *value {
+            l = mid + 1;
+        } else {
+            r = mid;
         }
-        l
     }
+    l
+}
 
-    default fn upper_bound(&self, value: &T) -> usize {
-        // invariants: [0, l) < value & value <= [r, len)
-        unsafe {
-            std::intrinsics::prefetch_read_data(&self, 2);
+pub fn upper_bound<T: Ord>(keys: &[T], value: &T) -> usize {
+    // invariants: [0, l) <= value & value < [r, len)
+    prefetch(keys.as_ptr());
+    let mut l = 0;
+    let mut r = keys.len();
+    while l < r {
+        let mid = (l + r) / 2;
+        if keys[mid] <= *value {
+            l = mid + 1;
+        } else {
+            r = mid;
         }
-        let mut l = 0;
-        let mut r = self.len();
-        while l < r {
-            let mid = (l + r) / 2;
-            if self[mid] <= *value {
-                l = mid + 1;
-            } else {
-                r = mid;
-            }
+    }
+    l
+}
+
+pub fn linear_search<T: Ord>(keys: &[T], value: &T) -> usize {
+    let mut idx = 0;
+    while idx < keys.len() {
+        if value == &keys[idx] {
+            return idx;
         }
-        l
+        idx += 1;
     }
+    idx
 }
 
-impl SortedSearch<i32> for [i32] {
-    #[must_use]
-    #[cfg(all(
-        any(target_arch = "x86", target_arch = "x86_64"),
-        all(target_feature = "avx", target_feature = "avx2")
-    ))]
-    fn lower_bound(&self, value: &i32) -> usize {
-        use std::arch::x86_64::*;
-        let rounded = (self.len() / 8) * 8;
-        unsafe {
-            let value = _mm256_set1_epi32(*value);
-            for i in (0..rounded).step_by(8) {
-                let addr = &self[i] as *const i32 as *const __m256i;
-                let vec: __m256i = _mm256_loadu_si256(addr);
-                let cmp: __m256i = _mm256_cmpgt_epi32(value, vec);
-                let mask = _mm256_movemask_epi8(cmp);
-                if mask != -1 {
-                    // 0xffffffff
-                    return i + (std::intrinsics::cttz(!mask) / 4) as usize;
-                }
-            }
+// Searches over byte strings by a borrowed `&[u8]`: keys compare as plain
+//   slices, whatever their owning type
+pub fn lower_bound_bytes<B: AsRef<[u8]>>(keys: &[B], value: &[u8]) -> usize {
+    // invariants: [0, l) < value & value <= [r, len)
+    prefetch(keys.as_ptr());
+    let mut l = 0;
+    let mut r = keys.len();
+    while l < r {
+        let mid = (l + r) / 2;
+        if keys[mid].as_ref() < value {
+            l = mid + 1;
+        } else {
+            r = mid;
         }
-        for (i, ele) in self.iter().enumerate().skip(rounded) {
-            if ele >= value {
-                return i;
-            }
+    }
+    l
+}
+
+pub fn upper_bound_bytes<B: AsRef<[u8]>>(keys: &[B], value: &[u8]) -> usize {
+    // invariants: [0, l) <= value & value < [r, len)
+    prefetch(keys.as_ptr());
+    let mut l = 0;
+    let mut r = keys.len();
+    while l < r {
+        let mid = (l + r) / 2;
+        if keys[mid].as_ref() <= value {
+            l = mid + 1;
+        } else {
+            r = mid;
         }
-        self.len()
     }
+    l
+}
 
-    #[must_use]
-    #[cfg(all(
-        any(target_arch = "x86", target_arch = "x86_64"),
-        all(target_feature = "avx", target_feature = "avx2")
-    ))]
-    fn upper_bound(&self, value: &i32) -> usize {
-        use std::arch::x86_64::*;
-        let rounded = (self.len() / 8) * 8;
-        unsafe {
-            let value = _mm256_set1_epi32(*value);
-            for i in (0..rounded).step_by(8) {
-                let addr = &self[i] as *const i32 as *const __m256i;
-                let vec: __m256i = _mm256_loadu_si256(addr);
-                let cmp: __m256i = _mm256_cmpgt_epi32(vec, value);
-                let mask = _mm256_movemask_epi8(cmp);
-                if mask != 0 {
-                    return i + (std::intrinsics::cttz(mask) / 4) as usize;
-                }
+pub fn linear_search_bytes<B: AsRef<[u8]>>(keys: &[B], value: &[u8]) -> usize {
+    keys.iter()
+        .position(|key| key.as_ref() == value)
+        .unwrap_or(keys.len())
+}
+
+// Length of the shortest prefix of `right` that still sorts after `left`,
+//   for `left < right`
+pub fn separator_len(left: &[u8], right: &[u8]) -> usize {
+    debug_assert!(left < right);
+    let common = left.iter().zip(right).take_while(|(a, b)| a == b).count();
+    common + 1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SimdLevel {
+    Scalar,
+    Sse42,
+    Avx2,
+}
+
+impl SimdLevel {
+    // The widest instruction set the running CPU supports
+    pub fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                return Self::Avx2;
             }
-        }
-        for (i, ele) in self.iter().enumerate().skip(rounded) {
-            if ele > value {
-                return i;
+            if is_x86_feature_detected!("sse4.2") {
+                return Self::Sse42;
             }
         }
-        self.len()
+        Self::Scalar
     }
 }
 
-pub trait LinearSearch<T> {
-    fn linear_search(&self, value: &T) -> usize;
+// In-node search routines for one key type, picked once per tree so the
+//   hot loops don't re-check CPU features
+pub struct Kernels<K> {
+    level: SimdLevel,
+    lower_bound: fn(&[K], &K) -> usize,
+    upper_bound: fn(&[K], &K) -> usize,
+    linear_search: fn(&[K], &K) -> usize,
 }
 
-impl<T: Ord> LinearSearch<T> for [T] {
-    default fn linear_search(&self, key: &T) -> usize {
-        let mut idx = 0;
-        while idx < self.len() {
-            if key == &self[idx] {
-                return idx;
-            }
-            idx += 1;
-        }
-        idx
+impl<K> Clone for Kernels<K> {
+    fn clone(&self) -> Self {
+        *self
     }
 }
+impl<K> Copy for Kernels<K> {}
 
-impl LinearSearch<i32> for [i32] {
+impl<K> std::fmt::Debug for Kernels<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Kernels({:?})", self.level)
+    }
+}
+
+impl<K: Ord> Kernels<K> {
     #[must_use]
-    #[cfg(all(
-        any(target_arch = "x86", target_arch = "x86_64"),
-        all(target_feature = "avx", target_feature = "avx2")
-    ))]
-    fn linear_search(&self, value: &i32) -> usize {
-        use std::arch::x86_64::*;
-        unsafe {
-            std::intrinsics::prefetch_read_data(&self, 2);
+    pub fn scalar() -> Self {
+        Self {
+            level: SimdLevel::Scalar,
+            lower_bound,
+            upper_bound,
+            linear_search,
+        }
+    }
+
+    // `level` must be supported by the running CPU
+    pub(crate) fn with(
+        level: SimdLevel,
+        lower_bound: fn(&[K], &K) -> usize,
+        upper_bound: fn(&[K], &K) -> usize,
+        linear_search: fn(&[K], &K) -> usize,
+    ) -> Self {
+        assert!(level <= SimdLevel::detect());
+        Self {
+            level,
+            lower_bound,
+            upper_bound,
+            linear_search,
         }
-        let rounded = (self.len() / 8) * 8;
-        unsafe {
-            let value = _mm256_set1_epi32(*value);
-            for i in (0..rounded).step_by(8) {
-                let addr = &self[i] as *const i32 as *const __m256i;
-                let vec: __m256i = _mm256_loadu_si256(addr);
-                let cmp: __m256i = _mm256_cmpeq_epi32(vec, value);
-                let mask = _mm256_movemask_epi8(cmp);
-                if mask != 0 {
-                    return i + (std::intrinsics::cttz(mask) / 4) as usize;
+    }
+
+    pub fn level(&self) -> SimdLevel {
+        self.level
+    }
+
+    pub fn lower_bound(&self, keys: &[K], value: &K) -> usize {
+        (self.lower_bound)(keys, value)
+    }
+
+    pub fn upper_bound(&self, keys: &[K], value: &K) -> usize {
+        (self.upper_bound)(keys, value)
+    }
+
+    pub fn linear_search(&self, keys: &[K], value: &K) -> usize {
+        (self.linear_search)(keys, value)
+    }
+}
+
+// Keys usable in a tree. Types with vectorized searches override `kernels`
+//   and fall back to a narrower `level` than asked for when they must.
+pub trait SearchKey: Ord + Sized {
+    fn kernels(level: SimdLevel) -> Kernels<Self> {
+        let _ = level;
+        Kernels::scalar()
+    }
+
+    // The key a split pushes up between two leaves: any `s` with
+    //   `left < s <= right` routes correctly. Variable-length keys return
+    //   a short prefix of `right`, so that internal nodes stay small.
+    fn separator(left: &Self, right: &Self) -> Self
+    where
+        Self: Clone,
+    {
+        let _ = left;
+        right.clone()
+    }
+}
+
+macro_rules! scalar_search_key {
+    ($($t:ty),*) => {
+        $(impl SearchKey for $t {})*
+    };
+}
+
+scalar_search_key!(u128, usize, i128, isize, char, bool);
+
+// Byte strings compare bytewise, and `String` orders exactly like its bytes,
+//   so all of them are searched as borrowed `&[u8]`
+fn bytes_kernels<B: Ord + AsRef<[u8]>>() -> Kernels<B> {
+    Kernels::with(
+        SimdLevel::Scalar,
+        |keys, value| lower_bound_bytes(keys, value.as_ref()),
+        |keys, value| upper_bound_bytes(keys, value.as_ref()),
+        |keys, value| linear_search_bytes(keys, value.as_ref()),
+    )
+}
+
+macro_rules! bytes_search_key {
+    ($($t:ty),*) => {
+        $(
+            impl SearchKey for $t {
+                fn kernels(level: SimdLevel) -> Kernels<Self> {
+                    let _ = level;
+                    bytes_kernels()
+                }
+
+                fn separator(left: &Self, right: &Self) -> Self {
+                    let len = separator_len(left.as_ref(), right.as_ref());
+                    right[..len].into()
                 }
             }
+        )*
+    };
+}
+
+bytes_search_key!(Vec<u8>, Box<[u8]>);
+
+impl SearchKey for String {
+    fn kernels(level: SimdLevel) -> Kernels<Self> {
+        let _ = level;
+        bytes_kernels()
+    }
+
+    fn separator(left: &Self, right: &Self) -> Self {
+        let mut len = separator_len(left.as_bytes(), right.as_bytes());
+        // a longer prefix still sorts after `left`
+        while !right.is_char_boundary(len) {
+            len += 1;
         }
-        for (i, ele) in self.iter().enumerate().skip(rounded) {
-            if ele == value {
-                return i;
+        right[..len].to_string()
+    }
+}
+
+macro_rules! simd_search_key {
+    ($($t:ty => $lower:ident, $upper:ident, $linear:ident;)*) => {
+        $(
+            impl SearchKey for $t {
+                fn kernels(level: SimdLevel) -> Kernels<Self> {
+                    #[cfg(target_arch = "x86_64")]
+                    {
+                        use super::simd::{avx2, sse42};
+                        match level {
+                            SimdLevel::Avx2 => {
+                                return Kernels::with(level, avx2::$lower, avx2::$upper, avx2::$linear)
+                            }
+                            SimdLevel::Sse42 => {
+                                return Kernels::with(
+                                    level,
+                                    sse42::$lower,
+                                    sse42::$upper,
+                                    sse42::$linear,
+                                )
+                            }
+                            SimdLevel::Scalar => {}
+                        }
+                    }
+                    let _ = level;
+                    Kernels::scalar()
+                }
             }
-        }
-        self.len()
+        )*
+    };
+}
+
+simd_search_key! {
+    i8 => lower_bound_i8, upper_bound_i8, linear_search_i8;
+    u8 => lower_bound_u8, upper_bound_u8, linear_search_u8;
+    i16 => lower_bound_i16, upper_bound_i16, linear_search_i16;
+    u16 => lower_bound_u16, upper_bound_u16, linear_search_u16;
+    i32 => lower_bound_i32, upper_bound_i32, linear_search_i32;
+    u32 => lower_bound_u32, upper_bound_u32, linear_search_u32;
+    i64 => lower_bound_i64, upper_bound_i64, linear_search_i64;
+    u64 => lower_bound_u64, upper_bound_u64, linear_search_u64;
+}
+
+pub trait SortedSearch<T: ?Sized> {
+    fn lower_bound(&self, value: &T) -> usize;
+    fn upper_bound(&self, value: &T) -> usize;
+}
+
+impl<T: Ord> SortedSearch<T> for [T] {
+    fn lower_bound(&self, value: &T) -> usize {
+        lower_bound(self, value)
+    }
+
+    fn upper_bound(&self, value: &T) -> usize {
+        upper_bound(self, value)
+    }
+}
+
+// Byte-string keys can be searched for by a borrowed `&[u8]`
+impl<B: AsRef<[u8]>> SortedSearch<[u8]> for [B] {
+    fn lower_bound(&self, value: &[u8]) -> usize {
+        lower_bound_bytes(self, value)
+    }
+
+    fn upper_bound(&self, value: &[u8]) -> usize {
+        upper_bound_bytes(self, value)
+    }
+}
+
+pub trait LinearSearch<T> {
+    fn linear_search(&self, value: &T) -> usize;
+}
+
+impl<T: Ord> LinearSearch<T> for [T] {
+    fn linear_search(&self, value: &T) -> usize {
+        linear_search(self, value)
     }
 }
@@ -1,22 +1,22 @@
-use super::node::MAX_LEN;
+use super::node::DEFAULT_FANOUT;
 use std::mem::MaybeUninit;
 use std::ops::{Index, IndexMut};
+use std::ptr;
 
-const MAX_CAPACITY: usize = MAX_LEN + 1;
-
-#[derive(Clone)]
+// Fixed-capacity vector stored inline in a node.
+//   invariant: data[..len] is initialized, data[len..] is not
 #[repr(C)]
-pub struct MyVector<K> {
+pub struct MyVector<K, const C: usize = DEFAULT_FANOUT> {
     len: usize,
-    data: [K; MAX_CAPACITY],
+    data: [MaybeUninit<K>; C],
 }
 
-impl<T> MyVector<T> {
+impl<T, const C: usize> MyVector<T, C> {
     #[must_use]
     pub fn new() -> Self {
         Self {
             len: 0,
-            data: unsafe { MaybeUninit::uninit().assume_init() },
+            data: [const { MaybeUninit::uninit() }; C],
         }
     }
 
@@ -24,27 +24,38 @@ impl<T> MyVector<T> {
         self.len
     }
 
+    fn as_ptr(&self) -> *const T {
+        self.data.as_ptr() as *const T
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut T {
+        self.data.as_mut_ptr() as *mut T
+    }
+
     pub fn push(&mut self, value: T) {
-        assert!(self.len < MAX_CAPACITY);
-        self.data[self.len] = value;
+        assert!(self.len < C);
+        self.data[self.len].write(value);
         self.len += 1;
     }
 
     pub fn insert(&mut self, k: usize, v: T) {
-        assert!(self.len < MAX_CAPACITY);
+        assert!(self.len < C);
         assert!(k <= self.len());
-        self.len += 1;
-        for i in (k + 1..self.len).rev() {
-            self.data.swap(i, i - 1);
+        unsafe {
+            let p = self.as_mut_ptr().add(k);
+            ptr::copy(p, p.add(1), self.len - k);
+            ptr::write(p, v);
         }
-        self.data[k] = v;
+        self.len += 1;
     }
 
+    // Moves the last `size` elements into a new vector
     pub fn split_off(&mut self, size: usize) -> Self {
-        assert_eq!(self.len() >= size, true);
+        assert!(self.len() >= size);
         let mut vec = Self::new();
-        for (count, idx) in ((self.len - size)..self.len).enumerate() {
-            std::mem::swap(&mut vec[count], &mut self.data[idx]);
+        unsafe {
+            let src = self.as_ptr().add(self.len - size);
+            ptr::copy_nonoverlapping(src, vec.as_mut_ptr(), size);
         }
         self.len -= size;
         vec.len = size;
@@ -56,50 +67,80 @@ impl<T> MyVector<T> {
             None
         } else {
             self.len -= 1;
-            Some(std::mem::replace(&mut self.data[self.len], unsafe {
-                std::mem::MaybeUninit::uninit().assume_init()
-            }))
+            // the slot past `len` is treated as uninitialized from now on
+            Some(unsafe { self.data[self.len].assume_init_read() })
+        }
+    }
+
+    pub fn remove(&mut self, idx: usize) -> T {
+        assert!(idx < self.len);
+        unsafe {
+            let p = self.as_mut_ptr().add(idx);
+            let value = ptr::read(p);
+            ptr::copy(p.add(1), p, self.len - idx - 1);
+            self.len -= 1;
+            value
         }
     }
 
+    pub fn clear(&mut self) {
+        let elems: *mut [T] = &mut **self;
+        // emptied first, so a panicking destructor can't lead to a double drop
+        self.len = 0;
+        unsafe { ptr::drop_in_place(elems) };
+    }
+
     pub fn is_empty(&self) -> bool {
         self.len == 0
     }
 
-    pub fn last(&self) -> Option<&T> {
-        if self.len > 1 {
-            Some(&self.data[self.len - 1])
-        } else {
-            None
+    // Replaces the contents with the elements drained from `vec`
+    pub fn clone_from_vec(&mut self, vec: &mut Vec<T>) {
+        assert!(vec.len() <= C);
+        self.clear();
+        for data in vec.drain(..) {
+            self.push(data);
         }
     }
 }
 
-impl<T: Clone> MyVector<T> {
-    pub fn clone_from_vec(&mut self, vec: &mut Vec<T>) {
-        assert!(vec.len() <= MAX_CAPACITY);
-        self.len = 0;
-        for data in vec.drain(..) {
-            self.push(data);
+impl<T, const C: usize> Drop for MyVector<T, C> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T, const C: usize> Default for MyVector<T, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone, const C: usize> Clone for MyVector<T, C> {
+    fn clone(&self) -> Self {
+        let mut vec = Self::new();
+        for data in self.iter() {
+            vec.push(data.clone());
         }
+        vec
     }
 }
 
-impl<T> std::ops::Deref for MyVector<T> {
+impl<T, const C: usize> std::ops::Deref for MyVector<T, C> {
     type Target = [T];
 
     fn deref(&self) -> &[T] {
-        unsafe { std::slice::from_raw_parts(&self.data[0] as *const _, self.len) }
+        unsafe { std::slice::from_raw_parts(self.as_ptr(), self.len) }
     }
 }
 
-impl<T> std::ops::DerefMut for MyVector<T> {
+impl<T, const C: usize> std::ops::DerefMut for MyVector<T, C> {
     fn deref_mut(&mut self) -> &mut [T] {
-        unsafe { std::slice::from_raw_parts_mut(&mut self.data[0] as *mut _, self.len) }
+        unsafe { std::slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) }
     }
 }
 
-impl<'a, K> IntoIterator for &'a MyVector<K> {
+impl<'a, K, const C: usize> IntoIterator for &'a MyVector<K, C> {
     type Item = &'a K;
     type IntoIter = std::slice::Iter<'a, K>;
 
@@ -108,7 +149,7 @@ impl<'a, K> IntoIterator for &'a MyVector<K> {
     }
 }
 
-impl<'a, K> IntoIterator for &'a mut MyVector<K> {
+impl<'a, K, const C: usize> IntoIterator for &'a mut MyVector<K, C> {
     type Item = &'a mut K;
     type IntoIter = std::slice::IterMut<'a, K>;
 
@@ -117,46 +158,50 @@ impl<'a, K> IntoIterator for &'a mut MyVector<K> {
     }
 }
 
-impl<K> Index<usize> for MyVector<K> {
+impl<K, const C: usize> Index<usize> for MyVector<K, C> {
     type Output = K;
 
     fn index(&self, index: usize) -> &Self::Output {
-        &self.data[index]
+        &(**self)[index]
     }
 }
 
-impl<K> IndexMut<usize> for MyVector<K> {
+impl<K, const C: usize> IndexMut<usize> for MyVector<K, C> {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.data[index]
+        &mut (**self)[index]
     }
 }
 
-impl<K: std::fmt::Debug> std::fmt::Debug for MyVector<K> {
+impl<K: std::fmt::Debug, const C: usize> std::fmt::Debug for MyVector<K, C> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut s = "MyVector:: [ ".to_string();
-        for i in 0..self.len() {
-            s += &format!("{:?} ", self.data[i]);
+        for data in self.iter() {
+            s += &format!("{:?} ", data);
         }
         write!(f, "{}]", s)
     }
 }
 
-impl<T: Clone> From<Vec<T>> for MyVector<T> {
-    #[must_use]
+impl<T, const C: usize> From<Vec<T>> for MyVector<T, C> {
     fn from(vec: Vec<T>) -> Self {
-        assert!(vec.len() <= MAX_CAPACITY);
+        assert!(vec.len() <= C);
         let mut myvec = Self::new();
-        myvec.len = vec.len();
-        myvec.clone_from_slice(&vec);
+        for data in vec {
+            myvec.push(data);
+        }
         myvec
     }
 }
 
-impl<T: Clone> Into<Vec<T>> for MyVector<T> {
-    fn into(self) -> Vec<T> {
-        self.to_vec()
+impl<T, const C: usize> From<MyVector<T, C>> for Vec<T> {
+    fn from(mut val: MyVector<T, C>) -> Self {
+        let mut vec = Vec::with_capacity(val.len);
+        unsafe {
+            ptr::copy_nonoverlapping(val.as_ptr(), vec.as_mut_ptr(), val.len);
+            vec.set_len(val.len);
+        }
+        // the elements now belong to `vec`
+        val.len = 0;
+        vec
     }
 }
-
-#[derive(Debug)]
-enum MyVectorError {}
@@ -0,0 +1,303 @@
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use super::query::Query;
+use super::snapshot::{check_tag, crc32, invalid, write_tag, Codec};
+
+// Log layout, integers little-endian:
+//   magic, version: u32, key tag, value tag, then one record per batch:
+//   payload length: u32, CRC-32 of the payload: u32, CRC-32 of these first
+//   8 bytes: u32, payload. A payload is the number of queries: u32,
+//   followed by the queries, each a tag byte and its fields.
+const MAGIC: &[u8; 8] = b"PALMWAL\0";
+const VERSION: u32 = 2;
+const RECORD_HEADER: usize = 12;
+
+const INSERTION: u8 = 0;
+const DELETION: u8 = 1;
+const UPDATE: u8 = 2;
+const INSERT_IF_ABSENT: u8 = 3;
+const COMPARE_AND_SWAP: u8 = 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    // `fsync` after every record, so an applied batch survives a power loss
+    #[default]
+    EveryBatch,
+    // leave flushing to the OS; an applied batch survives a crash of the
+    //   process, but not of the machine
+    Never,
+}
+
+// What a log is kept in: a `File`, or a wrapper around one (e.g. to
+//   inject I/O errors)
+pub trait LogFile: Read + Write + Seek + Send {
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+    fn sync_data(&mut self) -> io::Result<()>;
+    fn sync_all(&mut self) -> io::Result<()>;
+}
+
+impl LogFile for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+}
+
+// Appends the writes of each batch before the batch is applied
+pub(crate) trait BatchLog<K, V>: Send {
+    fn append(&mut self, queries: &[Query<K, V>]) -> io::Result<()>;
+    fn truncate(&mut self) -> io::Result<()>;
+}
+
+pub struct Wal<K, V, L = File> {
+    file: L,
+    sync: SyncPolicy,
+    // length of the file header, where an empty log ends
+    start: u64,
+    _marker: PhantomData<fn(K, V)>,
+}
+
+impl<K: Codec, V: Codec> Wal<K, V> {
+    // Opens the log at `path`, creating it if needed, see `Wal::open_file`
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        sync: SyncPolicy,
+    ) -> io::Result<(Self, Vec<Vec<Query<K, V>>>)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Self::open_file(file, sync)
+    }
+}
+
+impl<K: Codec, V: Codec, L: LogFile> Wal<K, V, L> {
+    // Reads the log in `file` (empty for a new log) and returns it along
+    //   with the batches it holds. A crash can only tear the last record, so
+    //   a damaged last record is dropped from the file; damage anywhere else
+    //   is an error.
+    pub fn open_file(mut file: L, sync: SyncPolicy) -> io::Result<(Self, Vec<Vec<Query<K, V>>>)> {
+        let mut header = Vec::new();
+        header.extend_from_slice(MAGIC);
+        VERSION.encode(&mut header)?;
+        write_tag(&mut header, K::TAG)?;
+        write_tag(&mut header, V::TAG)?;
+        let mut bytes = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut bytes)?;
+
+        let mut batches = Vec::new();
+        let end = if bytes.len() < header.len() && header.starts_with(&bytes) {
+            // created, or torn while writing the header
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&header)?;
+            header.len() as u64
+        } else {
+            check_header::<K, V>(&bytes)?;
+            let mut pos = header.len();
+            while pos < bytes.len() {
+                match read_record(&bytes[pos..])? {
+                    Some((batch, len)) => {
+                        batches.push(batch);
+                        pos += len;
+                    }
+                    // torn, the tail of the file is dropped
+                    None => break,
+                }
+            }
+            file.set_len(pos as u64)?;
+            pos as u64
+        };
+        file.sync_all()?;
+        file.seek(SeekFrom::Start(end))?;
+        let wal = Self {
+            file,
+            sync,
+            start: header.len() as u64,
+            _marker: PhantomData,
+        };
+        Ok((wal, batches))
+    }
+}
+
+impl<K: Codec, V: Codec, L: LogFile> BatchLog<K, V> for Wal<K, V, L>
+where
+    K: Send,
+    V: Send,
+{
+    fn append(&mut self, queries: &[Query<K, V>]) -> io::Result<()> {
+        let mut payload = Vec::new();
+        0u32.encode(&mut payload)?;
+        let mut count = 0usize;
+        for query in queries {
+            count += encode_query(query, &mut payload)? as usize;
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        let len = u32::try_from(payload.len())
+            .map_err(|_| invalid("batch is too large for the write-ahead log"))?;
+        // fewer queries than bytes
+        payload[..4].copy_from_slice(&(count as u32).to_le_bytes());
+
+        // written in one go, so only the last record can ever be torn
+        let mut record = Vec::with_capacity(payload.len() + RECORD_HEADER);
+        len.encode(&mut record)?;
+        crc32(0, &payload).encode(&mut record)?;
+        crc32(0, &record).encode(&mut record)?;
+        record.extend_from_slice(&payload);
+        let end = self.file.stream_position()?;
+        let written = self.file.write_all(&record).and_then(|()| match self.sync {
+            SyncPolicy::EveryBatch => self.file.sync_data(),
+            SyncPolicy::Never => Ok(()),
+        });
+        if let Err(error) = written {
+            // a partial record would hide every later one, and a whole one
+            //   would be replayed although the batch was never applied
+            self.file.set_len(end)?;
+            self.file.seek(SeekFrom::Start(end))?;
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(self.start)?;
+        self.file.seek(SeekFrom::Start(self.start))?;
+        self.file.sync_all()
+    }
+}
+
+fn check_header<K: Codec, V: Codec>(mut bytes: &[u8]) -> io::Result<()> {
+    let reader = &mut bytes;
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a write-ahead log"));
+    }
+    if u32::decode(reader)? != VERSION {
+        return Err(invalid("unsupported write-ahead log version"));
+    }
+    check_tag(reader, "log", K::TAG)?;
+    check_tag(reader, "log", V::TAG)
+}
+
+// Decodes the record at the start of `bytes`, returning its batch and its
+//   length, or `None` if a crash tore it. Only the last record can be torn:
+//   it either ends within its header, or has an intact header and runs past
+//   the end of the file or fails its CRC. Zeroes after the damage, as in a
+//   preallocated tail, count as the end of the file.
+fn read_record<K: Codec, V: Codec>(bytes: &[u8]) -> io::Result<Option<(Vec<Query<K, V>>, usize)>> {
+    let zeroes_from = |pos: usize| bytes[pos.min(bytes.len())..].iter().all(|&b| b == 0);
+    if bytes.len() < RECORD_HEADER {
+        return Ok(None);
+    }
+    let mut reader = bytes;
+    let len = u32::decode(&mut reader)? as usize;
+    let crc = u32::decode(&mut reader)?;
+    if crc32(0, &bytes[..8]) != u32::decode(&mut reader)? {
+        if zeroes_from(RECORD_HEADER) {
+            return Ok(None);
+        }
+        return Err(invalid("write-ahead log is corrupted"));
+    }
+    let end = RECORD_HEADER + len;
+    if end > bytes.len() {
+        return Ok(None);
+    }
+    let mut payload = &reader[..len];
+    if len < 4 || crc32(0, payload) != crc {
+        if zeroes_from(end) {
+            return Ok(None);
+        }
+        return Err(invalid("write-ahead log is corrupted"));
+    }
+    let count = u32::decode(&mut payload)?;
+    let mut batch = Vec::new();
+    for _ in 0..count {
+        batch.push(decode_query(&mut payload)?);
+    }
+    Ok(Some((batch, end)))
+}
+
+// Writes `query` if it modifies the tree, returning whether it did
+fn encode_query<K: Codec, V: Codec, W: Write>(
+    query: &Query<K, V>,
+    writer: &mut W,
+) -> io::Result<bool> {
+    match query {
+        Query::Insertion { k, v } => {
+            INSERTION.encode(writer)?;
+            k.encode(writer)?;
+            v.encode(writer)?;
+        }
+        Query::Deletion { k } => {
+            DELETION.encode(writer)?;
+            k.encode(writer)?;
+        }
+        Query::Update { k, op } => {
+            UPDATE.encode(writer)?;
+            k.encode(writer)?;
+            op.encode(writer)?;
+        }
+        Query::InsertIfAbsent { k, v } => {
+            INSERT_IF_ABSENT.encode(writer)?;
+            k.encode(writer)?;
+            v.encode(writer)?;
+        }
+        Query::CompareAndSwap { k, expected, new } => {
+            COMPARE_AND_SWAP.encode(writer)?;
+            k.encode(writer)?;
+            expected.encode(writer)?;
+            new.encode(writer)?;
+        }
+        Query::Retrieval { .. }
+        | Query::Range { .. }
+        | Query::Floor { .. }
+        | Query::Ceiling { .. }
+        | Query::Predecessor { .. }
+        | Query::Successor { .. } => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn decode_query<K: Codec, V: Codec, R: Read>(reader: &mut R) -> io::Result<Query<K, V>> {
+    let query = match u8::decode(reader)? {
+        INSERTION => Query::Insertion {
+            k: K::decode(reader)?,
+            v: V::decode(reader)?,
+        },
+        DELETION => Query::Deletion {
+            k: K::decode(reader)?,
+        },
+        UPDATE => Query::Update {
+            k: K::decode(reader)?,
+            op: V::decode(reader)?,
+        },
+        INSERT_IF_ABSENT => Query::InsertIfAbsent {
+            k: K::decode(reader)?,
+            v: V::decode(reader)?,
+        },
+        COMPARE_AND_SWAP => Query::CompareAndSwap {
+            k: K::decode(reader)?,
+            expected: V::decode(reader)?,
+            new: V::decode(reader)?,
+        },
+        _ => return Err(invalid("unknown query in write-ahead log")),
+    };
+    Ok(query)
+}
@@ -1,44 +1,121 @@
+use super::balance::LeafChunks;
+use super::handshake::Handshake;
+use super::node::{Node, DEFAULT_FANOUT};
 use super::nodeptr::NodePtr;
 use super::notthreadsafe::NotThreadSafe;
-use super::query::Query;
+use super::query::{Query, Response};
+use super::sync::mpsc::{channel, Receiver, Sender};
+use super::sync::{thread, Barrier};
 use super::tree::*;
+use super::util::SearchKey;
+use std::cell::Cell;
 use std::collections::VecDeque;
-use std::sync::{
-    mpsc::{channel, Receiver, Sender},
-    Arc, Barrier,
-};
-use std::thread;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub struct Worker<K, V>
+// How the leaves of a batch are shared out among the threads
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Partitioning {
+    // every thread applies the leaves its share of the sorted batch falls
+    //   into; a leaf spanning several shares goes to the first of them
+    #[default]
+    Keys,
+    // the leaves are cut into chunks of similar work, and threads done with
+    //   their own chunks take the others'; costs two more barriers, but
+    //   keeps a skewed batch from piling up on one thread
+    Leaves,
+}
+
+// Where the threads spent a batch, see `PalmWrapper::report`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BatchReport {
+    // wall time of `run_batch`
+    pub total: Duration,
+    pub threads: Vec<ThreadReport>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ThreadReport {
+    // including the two barriers of the sample sort
+    pub sort: Duration,
+    pub search: Duration,
+    // waiting for the other threads once the leaves are found (and around
+    //   the chunks, with `Partitioning::Leaves`)
+    pub barrier: Duration,
+    // range scans, with the barriers around them
+    pub scan: Duration,
+    // leaves first, then one per internal level
+    pub levels: Vec<LevelReport>,
+    // thread 0 only
+    pub root: Duration,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LevelReport {
+    pub redistribute: Duration,
+    pub apply: Duration,
+    // waiting for the neighbouring threads in `point_to_point_sync`
+    pub sync: Duration,
+}
+
+impl ThreadReport {
+    pub fn waiting(&self) -> Duration {
+        self.barrier + self.levels.iter().map(|l| l.sync).sum::<Duration>()
+    }
+
+    pub fn busy(&self) -> Duration {
+        let levels: Duration = self.levels.iter().map(|l| l.redistribute + l.apply).sum();
+        self.sort + self.search + self.scan + levels + self.root
+    }
+}
+
+// Time since `*since`, which moves on to now
+fn lap(since: &mut Instant) -> Duration {
+    let now = Instant::now();
+    let elapsed = now - *since;
+    *since = now;
+    elapsed
+}
+
+pub(crate) struct Worker<K, V, const F: usize>
 where
-    K: Ord + Clone + std::fmt::Debug,
+    K: SearchKey + Clone + std::fmt::Debug,
     V: Clone + std::fmt::Debug,
 {
     thread_index: usize,
-    tree: Arc<NotThreadSafe<Palm<K, V>>>,
+    tree: Arc<NotThreadSafe<Palm<K, V, F>>>,
     barrier: Arc<Barrier>,
-    q_query: Arc<Vec<Vec<NotThreadSafe<QueryMap<K, V>>>>>,
-    q_modif: Arc<Vec<Vec<NotThreadSafe<ModifMap<K, V>>>>>,
-    first: Arc<NotThreadSafe<Vec<Vec<Option<NodePtr<K, V>>>>>>,
-    last: Arc<NotThreadSafe<Vec<Vec<Option<NodePtr<K, V>>>>>>,
-    their_first: NotThreadSafe<NodePtr<K, V>>,
-    their_last: NotThreadSafe<NodePtr<K, V>>,
+    q_query: Arc<Vec<Vec<NotThreadSafe<QueryMap<K, V, F>>>>>,
+    q_modif: Arc<Vec<Vec<NotThreadSafe<ModifMap<K, V, F>>>>>,
+    handshake: Arc<Handshake<Node<K, V, F>>>,
+    // rounds of the handshake so far, the same on every thread
+    round: Cell<usize>,
+    // per thread, each written by its own thread only
+    has_range: Arc<Vec<NotThreadSafe<bool>>>,
+    samples: Arc<Vec<NotThreadSafe<Vec<K>>>>,
+    exchange: Arc<Vec<Vec<NotThreadSafe<Vec<Tagged<K, V>>>>>>,
+    chunks: Arc<LeafChunks<K, V, F>>,
+    their_first: NotThreadSafe<NodePtr<K, V, F>>,
+    their_last: NotThreadSafe<NodePtr<K, V, F>>,
 }
 
-impl<K, V> Worker<K, V>
+impl<K, V, const F: usize> Worker<K, V, F>
 where
-    K: 'static + Ord + Clone + std::fmt::Debug + Sync + Send,
-    V: 'static + Clone + std::fmt::Debug + Sync + Send,
+    K: 'static + SearchKey + Clone + std::fmt::Debug + Sync + Send,
+    V: 'static + Clone + PartialEq + std::fmt::Debug + Sync + Send,
 {
     #[must_use]
     pub fn new(
         thread_index: usize,
-        tree: Arc<NotThreadSafe<Palm<K, V>>>,
+        tree: Arc<NotThreadSafe<Palm<K, V, F>>>,
         barrier: Arc<Barrier>,
-        q_query: Arc<Vec<Vec<NotThreadSafe<QueryMap<K, V>>>>>,
-        q_modif: Arc<Vec<Vec<NotThreadSafe<ModifMap<K, V>>>>>,
-        first: Arc<NotThreadSafe<Vec<Vec<Option<NodePtr<K, V>>>>>>,
-        last: Arc<NotThreadSafe<Vec<Vec<Option<NodePtr<K, V>>>>>>,
+        q_query: Arc<Vec<Vec<NotThreadSafe<QueryMap<K, V, F>>>>>,
+        q_modif: Arc<Vec<Vec<NotThreadSafe<ModifMap<K, V, F>>>>>,
+        handshake: Arc<Handshake<Node<K, V, F>>>,
+        has_range: Arc<Vec<NotThreadSafe<bool>>>,
+        samples: Arc<Vec<NotThreadSafe<Vec<K>>>>,
+        exchange: Arc<Vec<Vec<NotThreadSafe<Vec<Tagged<K, V>>>>>>,
+        chunks: Arc<LeafChunks<K, V, F>>,
     ) -> Self {
         Self {
             thread_index,
@@ -46,8 +123,12 @@ where
             barrier,
             q_query,
             q_modif,
-            first,
-            last,
+            handshake,
+            round: Cell::new(0),
+            has_range,
+            samples,
+            exchange,
+            chunks,
             their_first: NotThreadSafe::new(NodePtr::new(std::ptr::null_mut())),
             their_last: NotThreadSafe::new(NodePtr::new(std::ptr::null_mut())),
         }
@@ -57,18 +138,16 @@ where
         self.barrier.wait();
     }
 
+    // Tells the neighbouring threads the first and last node of this
+    //   thread's layer, and learns theirs (see `Handshake`)
     pub fn point_to_point_sync<T: std::fmt::Debug + Clone>(
         &self,
-        depth: usize,
-        input: &[NotThreadSafe<VecDeque<(NodePtr<K, V>, Vec<T>)>>],
-        first: &mut [Vec<Option<NodePtr<K, V>>>],
-        last: &mut [Vec<Option<NodePtr<K, V>>>],
-        num_threads: usize,
+        input: &[NotThreadSafe<Layer<K, V, T, F>>],
     ) {
         let cur_layer = input[self.thread_index].get();
 
-        let mut my_first = cur_layer.front().map(|x| x.0);
-        let mut my_last = cur_layer.back().map(|x| x.0);
+        let mut my_first = cur_layer.front().map(|x| x.0.as_ptr());
+        let my_last = cur_layer.back().map(|x| x.0.as_ptr());
         if my_first == my_last {
             // if thread i has only one node,
             //   then thread (i-1) probably also wants thread (i+1)'s
@@ -77,56 +156,71 @@ where
             //   will fetch `my_first` from thread (i+1) instead
             my_first = None;
         }
-        assert_eq!(first[self.thread_index].len(), depth);
-        assert_eq!(last[self.thread_index].len(), depth);
-
-        let mut their_first = None;
-        let mut their_last = None;
-        let mut send_first = false;
-        let mut send_last = false;
-        while their_first.is_none() || their_last.is_none() || !send_first || !send_last {
-            if my_first.is_some() && !send_first {
-                // send my_first to i-1
-                first[self.thread_index].push(my_first);
-                send_first = true;
-            }
-            if my_last.is_some() && !send_last {
-                // send my_last to i+1
-                last[self.thread_index].push(my_last);
-                send_last = true;
-            }
-            if their_first.is_none() {
-                // fetch their first from i+1
-                if self.thread_index >= num_threads - 1 {
-                    their_first = Some(NodePtr::new(std::ptr::null_mut()));
-                } else if first[self.thread_index + 1].len() > depth {
-                    their_first = first[self.thread_index + 1][depth];
-                }
-            }
-            if their_first.is_some() && my_first.is_none() {
-                my_first = their_first;
-            }
-            if their_last.is_none() {
-                // fetch their last from i-1
-                if self.thread_index == 0 {
-                    their_last = Some(NodePtr::new(std::ptr::null_mut()));
-                } else if last[self.thread_index - 1].len() > depth {
-                    their_last = last[self.thread_index - 1][depth];
-                }
-            }
-            if their_last.is_some() && my_last.is_none() {
-                my_last = their_last;
+        self.round.set(self.round.get() + 1);
+        let (their_first, their_last) =
+            self.handshake
+                .exchange(self.thread_index, self.round.get(), my_first, my_last);
+        *self.their_first.get_mut() = NodePtr::new(their_first);
+        *self.their_last.get_mut() = NodePtr::new(their_last);
+    }
+
+    // Parallel sample sort (PSRS). Every thread sorts its chunk and
+    //   contributes regular samples; the pooled samples give t-1 splitters
+    //   and thread i ends up with the keys between splitters i-1 and i.
+    //   Equal keys always land on the same thread. The sort has to be
+    //   stable to preserve the order of queries: chunks hold increasing ids
+    //   and are concatenated in thread order before the final (run-merging)
+    //   stable sort, so equal keys stay in submission order.
+    fn sort_batch(&self, mut queries: Vec<Tagged<K, V>>, num_threads: usize) -> Vec<Tagged<K, V>> {
+        queries.sort_by(|a, b| a.1.cmp(&b.1));
+        if num_threads == 1 {
+            return queries;
+        }
+        let samples = self.samples[self.thread_index].get_mut();
+        samples.clear();
+        if !queries.is_empty() {
+            for j in 0..num_threads {
+                samples.push(queries[j * queries.len() / num_threads].1.get_key().clone());
             }
         }
-        std::mem::replace(self.their_first.get_mut(), their_first.unwrap());
-        std::mem::replace(self.their_last.get_mut(), their_last.unwrap());
+        self.global_sync();
+
+        // every thread picks the same splitters from the same samples
+        let mut pooled: Vec<&K> = self.samples.iter().flat_map(|s| s.get()).collect();
+        pooled.sort();
+        let splitters: Vec<&K> = (1..num_threads)
+            .filter_map(|j| pooled.get(j * pooled.len() / num_threads).copied())
+            .collect();
+        for to in (0..num_threads).rev() {
+            let cut = match to {
+                0 => 0,
+                _ => match splitters.get(to - 1) {
+                    Some(s) => queries.partition_point(|q| q.1.get_key() <= *s),
+                    None => queries.len(),
+                },
+            };
+            *self.exchange[self.thread_index][to].get_mut() = queries.split_off(cut);
+        }
+        self.global_sync();
+
+        let mut bucket = Vec::new();
+        for from in 0..num_threads {
+            bucket.append(self.exchange[from][self.thread_index].get_mut());
+        }
+        bucket.sort_by(|a, b| a.1.cmp(&b.1));
+        bucket
     }
 
-    pub fn execute(&self, mut queries: Vec<Query<K, V>>) -> Vec<(Query<K, V>, Option<V>)> {
+    pub fn execute(&self, queries: Vec<Tagged<K, V>>) -> (Vec<TaggedResponse<K, V>>, ThreadReport) {
         let depth = self.tree.get().depth;
         let num_threads = self.tree.get().num_threads;
-        self.first.get_mut()[self.thread_index].clear();
-        self.last.get_mut()[self.thread_index].clear();
+        let mut report = ThreadReport::default();
+        let mut now = Instant::now();
+        // Stage 0:
+        //   by sorting in advance, redistribution can be significantly simplified
+        let mut queries = self.sort_batch(queries, num_threads);
+        report.sort = lap(&mut now);
+        *self.has_range[self.thread_index].get_mut() = queries.iter().any(|(_, q)| q.is_scan());
         // Stage 1:
         //   1. divide tree queries among threads
         //   2. independently search for leaves for each query
@@ -134,8 +228,12 @@ where
             &mut queries,
             &self.q_query[0][self.thread_index],
             self.tree.get().root,
+            &self.tree.get().kernels,
         );
+        report.search = lap(&mut now);
         self.global_sync();
+        report.barrier = lap(&mut now);
+        let has_range = self.has_range.iter().any(|x| *x.get());
 
         // Stage 2:
         //   1. redistribute work to ensure no modification
@@ -149,44 +247,86 @@ where
             num_threads,
             self.their_last.get_mut(),
         );
-        let responses = Palm::apply_to_leaf_nodes(
-            &self.q_query[0][self.thread_index],
-            &self.q_modif[0][self.thread_index],
-            *self.their_last.get_mut(),
-        );
-        self.point_to_point_sync(
-            0,
-            &self.q_modif[0],
-            self.first.get_mut(),
-            self.last.get_mut(),
-            num_threads,
-        );
+        let mut leaves = LevelReport {
+            redistribute: lap(&mut now),
+            ..Default::default()
+        };
+        let mut ranges = Vec::new();
+        let mut responses = match self.tree.get().partitioning {
+            Partitioning::Keys => Palm::apply_to_leaf_nodes(
+                &self.q_query[0][self.thread_index],
+                &self.q_modif[0][self.thread_index],
+                *self.their_last.get_mut(),
+                &mut ranges,
+                self.tree.get().layout,
+                &self.tree.get().kernels,
+                self.tree.get().merge.as_deref(),
+            ),
+            Partitioning::Leaves => {
+                self.chunks.plan(
+                    self.thread_index,
+                    self.q_query[0][self.thread_index].get(),
+                    *self.their_last.get_mut(),
+                );
+                leaves.redistribute += lap(&mut now);
+                self.global_sync();
+                report.barrier += lap(&mut now);
+                let responses = self.apply_chunks(&mut ranges);
+                leaves.apply = lap(&mut now);
+                self.global_sync();
+                report.barrier += lap(&mut now);
+                self.chunks.gather(
+                    self.thread_index,
+                    self.q_modif[0][self.thread_index].get_mut(),
+                );
+                responses
+            }
+        };
+        leaves.apply += lap(&mut now);
+        if has_range {
+            // Range scans walk the leaf chain across other threads' leaves,
+            //   so every leaf has to be written before the scans start, and
+            //   no leaf may be merged away before they finish
+            self.global_sync();
+            Palm::scan_ranges(
+                &mut responses,
+                &ranges,
+                self.tree.get().root,
+                &self.tree.get().kernels,
+            );
+            self.global_sync();
+            report.scan = lap(&mut now);
+        }
+        self.point_to_point_sync(&self.q_modif[0]);
+        leaves.sync = lap(&mut now);
+        report.levels.push(leaves);
 
         // Stage 3:
         //   1. proceed in 'lock-step' up the tree, modify
         //     internal nodes and redistributing the works,
         //     up to the root
         let mut level_ptr = 0;
-        for d in 1..depth {
+        for _ in 1..depth {
             Palm::redistribute_work(
                 self.thread_index,
                 &self.q_modif[level_ptr],
                 num_threads,
                 self.their_last.get_mut(),
             );
+            let redistribute = lap(&mut now);
             Palm::apply_to_internal_nodes(
                 &self.q_modif[level_ptr][self.thread_index],
                 &self.q_modif[(level_ptr + 1) % 2][self.thread_index],
                 *self.their_last.get_mut(),
             );
+            let apply = lap(&mut now);
             level_ptr = (level_ptr + 1) % 2;
-            self.point_to_point_sync(
-                d,
-                &self.q_modif[level_ptr],
-                self.first.get_mut(),
-                self.last.get_mut(),
-                num_threads,
-            );
+            self.point_to_point_sync(&self.q_modif[level_ptr]);
+            report.levels.push(LevelReport {
+                redistribute,
+                apply,
+                sync: lap(&mut now),
+            });
         }
 
         // Stage 4:
@@ -195,6 +335,31 @@ where
         if self.thread_index == 0 {
             // handle the root
             Palm::handle_root(&self.tree, &self.q_modif[level_ptr]);
+            report.root = lap(&mut now);
+        }
+        (responses, report)
+    }
+
+    // Applies chunks of leaves, of any thread, until none are left
+    fn apply_chunks(
+        &self,
+        ranges: &mut Vec<(usize, NodePtr<K, V, F>)>,
+    ) -> Vec<TaggedResponse<K, V>> {
+        let tree = self.tree.get();
+        let mut responses = Vec::new();
+        ranges.clear();
+        while let Some((owner, chunk, leaves)) = self.chunks.take(self.thread_index) {
+            let next_map = self.chunks.modifs(owner, chunk);
+            next_map.clear();
+            Palm::apply_to_leaves(
+                leaves.iter(),
+                next_map,
+                &mut responses,
+                ranges,
+                tree.layout,
+                &tree.kernels,
+                tree.merge.as_deref(),
+            );
         }
         responses
     }
@@ -204,7 +369,7 @@ where
     ) -> (
         thread::JoinHandle<()>,
         Sender<Message<K, V>>,
-        Receiver<Vec<(Query<K, V>, Option<V>)>>,
+        Receiver<(Vec<TaggedResponse<K, V>>, ThreadReport)>,
     ) {
         let (in_sender, in_receiver) = channel();
         let (out_sender, out_receiver) = channel();
@@ -216,8 +381,7 @@ where
             let msg = receiver.recv().unwrap();
             match msg {
                 Message::Query(queries) => {
-                    let resp = self.execute(queries);
-                    sender.send(resp).unwrap();
+                    sender.send(self.execute(queries)).unwrap();
                 }
                 Message::Terminate => {
                     break;
@@ -228,29 +392,30 @@ where
     }
 }
 
-pub enum Message<K, V> {
-    Query(Vec<Query<K, V>>),
+pub(crate) enum Message<K, V> {
+    Query(Vec<Tagged<K, V>>),
     Terminate,
 }
 
-pub struct PalmWrapper<K, V> {
+pub struct PalmWrapper<K, V, const F: usize = DEFAULT_FANOUT> {
     pub seq_time: u128,
     pub par_time: u128,
+    report: BatchReport,
 
     num_threads: usize,
 
-    handles: Vec<std::thread::JoinHandle<()>>,
+    handles: Vec<thread::JoinHandle<()>>,
     senders: Vec<Sender<Message<K, V>>>,
-    receivers: Vec<Receiver<Vec<(Query<K, V>, Option<V>)>>>,
+    receivers: Vec<Receiver<(Vec<TaggedResponse<K, V>>, ThreadReport)>>,
 }
 
-impl<K, V> PalmWrapper<K, V>
+impl<K, V, const F: usize> PalmWrapper<K, V, F>
 where
-    K: 'static + Ord + Clone + std::fmt::Debug + Sync + Send,
-    V: 'static + Clone + std::fmt::Debug + Sync + Send,
+    K: 'static + SearchKey + Clone + std::fmt::Debug + Sync + Send,
+    V: 'static + Clone + PartialEq + std::fmt::Debug + Sync + Send,
 {
     #[must_use]
-    pub fn new(tree: Arc<NotThreadSafe<Palm<K, V>>>, num_threads: usize) -> Self {
+    pub(crate) fn new(tree: Arc<NotThreadSafe<Palm<K, V, F>>>, num_threads: usize) -> Self {
         let barrier = Arc::new(Barrier::new(num_threads));
         let q_query: Arc<Vec<_>> = Arc::new(
             (0..2)
@@ -270,12 +435,27 @@ where
                 })
                 .collect(),
         );
-        let first: Arc<NotThreadSafe<Vec<_>>> = Arc::new(NotThreadSafe::new(
-            (0..num_threads).map(|_| Vec::new()).collect(),
-        ));
-        let last: Arc<NotThreadSafe<Vec<_>>> = Arc::new(NotThreadSafe::new(
-            (0..num_threads).map(|_| Vec::new()).collect(),
-        ));
+        let handshake = Arc::new(Handshake::new(num_threads));
+        let has_range: Arc<Vec<_>> = Arc::new(
+            (0..num_threads)
+                .map(|_| NotThreadSafe::new(false))
+                .collect(),
+        );
+        let samples: Arc<Vec<_>> = Arc::new(
+            (0..num_threads)
+                .map(|_| NotThreadSafe::new(Vec::new()))
+                .collect(),
+        );
+        let chunks = Arc::new(LeafChunks::new(num_threads));
+        let exchange: Arc<Vec<Vec<_>>> = Arc::new(
+            (0..num_threads)
+                .map(|_| {
+                    (0..num_threads)
+                        .map(|_| NotThreadSafe::new(Vec::new()))
+                        .collect()
+                })
+                .collect(),
+        );
 
         let mut handles = Vec::new();
         let mut senders = Vec::new();
@@ -287,8 +467,11 @@ where
                 barrier.clone(),
                 q_modif.clone(),
                 q_query.clone(),
-                first.clone(),
-                last.clone(),
+                handshake.clone(),
+                has_range.clone(),
+                samples.clone(),
+                exchange.clone(),
+                chunks.clone(),
             );
             let (handle, sender, receiver) = worker.start();
             handles.push(handle);
@@ -299,6 +482,7 @@ where
         Self {
             seq_time: 0,
             par_time: 0,
+            report: BatchReport::default(),
             num_threads,
             handles,
             senders,
@@ -306,35 +490,43 @@ where
         }
     }
 
-    pub fn partition<T: Clone>(batch: &[T], t: usize) -> Vec<Vec<T>> {
-        batch
-            .chunks((batch.len() + t - 1) / t)
-            .map(|x| x.to_vec())
-            .collect()
+    // Per-thread, per-stage timings of the last batch
+    pub fn report(&self) -> &BatchReport {
+        &self.report
     }
 
-    pub fn run_batch(&mut self, queries: &mut Vec<Query<K, V>>) -> Vec<(Query<K, V>, Option<V>)> {
-        // by sorting in advance, redistribution can be significantly simplified
-        //   note that sort has to be stable to preserve the order of queries
+    pub fn run_batch(&mut self, queries: &[Query<K, V>]) -> Vec<(Query<K, V>, Response<K, V>)> {
+        let start = Instant::now();
+        // the batch is sorted by the workers themselves (`Worker::sort_batch`)
         let now = std::time::Instant::now();
-        queries.sort();
-        let mut partitions = Self::partition(&queries, self.num_threads);
+        let mut partitions = Palm::<K, V, F>::tag(queries, self.num_threads);
         self.seq_time += now.elapsed().as_micros();
 
         let now = std::time::Instant::now();
         for (i, queries) in partitions.drain(..).enumerate() {
             self.senders[i].send(Message::Query(queries)).unwrap();
         }
-        let mut results = Vec::new();
+        let mut responses = Vec::with_capacity(queries.len());
+        let mut threads = Vec::with_capacity(self.num_threads);
         for i in 0..self.num_threads {
-            results.extend(self.receivers[i].recv().unwrap());
+            let (thread_responses, report) = self.receivers[i].recv().unwrap();
+            responses.extend(thread_responses);
+            threads.push(report);
         }
         self.par_time += now.elapsed().as_micros();
+
+        let now = std::time::Instant::now();
+        let results = Palm::<K, V, F>::untag(queries.len(), responses.into_iter());
+        self.seq_time += now.elapsed().as_micros();
+        self.report = BatchReport {
+            total: start.elapsed(),
+            threads,
+        };
         results
     }
 }
 
-impl<K, V> Drop for PalmWrapper<K, V> {
+impl<K, V, const F: usize> Drop for PalmWrapper<K, V, F> {
     fn drop(&mut self) {
         for i in 0..self.num_threads {
             self.senders[i].send(Message::Terminate).unwrap();
@@ -0,0 +1,174 @@
+// Model checks of the worker protocol; run with
+//   cargo test --release --features loom --test loom
+// Besides the atomics, barriers and channels, loom tracks every access to
+//   the nodes and to the state the workers share through `NotThreadSafe`
+//   (queues, samples, exchanged queries), and reports two accesses, one of
+//   them a write, that no synchronization orders. It does so whatever the
+//   schedule that ran them, so a missing happens-before edge shows up even
+//   with few preemptions; more preemptions add other outcomes of the races
+//   on atomics, i.e. of the handshake, which `loom_exchange` covers.
+#![cfg(feature = "loom")]
+
+use palm::palm::handshake::Handshake;
+use palm::palm::map::PalmMap;
+use palm::palm::query::*;
+use palm::palm::worker::Partitioning;
+
+use loom::model::Builder;
+use loom::sync::Arc;
+use loom::thread;
+
+// Only the schedules with at most `bound` preemptions are explored: a whole
+//   batch has too many to explore them all, and a bound of 5 with 2 workers,
+//   or 2 with 3 workers, does not finish within minutes
+fn check(
+    num_threads: usize,
+    bound: usize,
+    partitioning: Partitioning,
+    batches: &[Vec<Query<u32, u32>>],
+    expected: &[u32],
+) {
+    let batches = batches.to_vec();
+    let expected = expected.to_vec();
+    let mut builder = Builder::new();
+    builder.preemption_bound = Some(bound);
+    builder.check(move || {
+        let mut tree = PalmMap::<u32, u32, 4>::new(num_threads);
+        tree.set_partitioning(partitioning);
+        for batch in &batches {
+            tree.apply_batch(batch);
+            tree.check_invariants().unwrap();
+        }
+        let keys: Vec<_> = tree.keys().copied().collect();
+        assert_eq!(keys, expected);
+    });
+}
+
+fn insertions(keys: impl Iterator<Item = u32>) -> Vec<Query<u32, u32>> {
+    keys.map(|k| Query::Insertion { k, v: k }).collect()
+}
+
+#[test]
+fn loom_root_split() {
+    // the leaves split, and the root handler grows the tree
+    check(
+        2,
+        3,
+        Partitioning::Keys,
+        &[insertions(0..8)],
+        &(0..8).collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn loom_internal_split() {
+    // the second batch splits leaves and internal nodes of every thread
+    let batches = [
+        insertions((0..12).map(|k| k * 4)),
+        insertions((0..12).map(|k| k * 4 + 2)),
+    ];
+    let mut expected: Vec<_> = (0..12).flat_map(|k| [k * 4, k * 4 + 2]).collect();
+    expected.sort();
+    check(2, 2, Partitioning::Keys, &batches, &expected);
+}
+
+#[test]
+fn loom_root_shrink() {
+    // the deletions merge nodes on both threads, and the root handler
+    //   shrinks the tree
+    let deletions = (2..12).map(|k| Query::Deletion { k }).collect();
+    check(
+        2,
+        2,
+        Partitioning::Keys,
+        &[insertions(0..12), deletions],
+        &[0, 1],
+    );
+}
+
+#[test]
+fn loom_forwarding() {
+    // with three threads, some hold no node above the leaves and forward
+    //   what their neighbours send, up to the new root
+    check(
+        3,
+        1,
+        Partitioning::Keys,
+        &[insertions(0..8)],
+        &(0..8).collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn loom_leaf_chunks() {
+    // both threads hold leaves in the second batch, and either may take
+    //   the other's chunk
+    let batches = [
+        insertions((0..12).map(|k| k * 4)),
+        insertions((0..12).map(|k| k * 4 + 2)),
+    ];
+    let mut expected: Vec<_> = (0..12).flat_map(|k| [k * 4, k * 4 + 2]).collect();
+    expected.sort();
+    check(2, 2, Partitioning::Leaves, &batches, &expected);
+}
+
+// Runs `rounds` rounds of the handshake, thread i holding `layers[i]` (its
+//   first and last node, or nothing) in every round, and checks what each
+//   thread learns against its nearest neighbours holding nodes
+fn check_exchange(layers: &[Option<(usize, usize)>], rounds: usize, bound: Option<usize>) {
+    let layers = layers.to_vec();
+    let mut builder = Builder::new();
+    builder.preemption_bound = bound;
+    builder.check(move || {
+        let num_threads = layers.len();
+        let handshake = Arc::new(Handshake::<u8>::new(num_threads));
+        let ptr = |node: usize| node as *mut u8;
+        let handles: Vec<_> = (0..num_threads)
+            .map(|index| {
+                let handshake = handshake.clone();
+                let layers = layers.clone();
+                thread::spawn(move || {
+                    for round in 1..=rounds {
+                        // a single node is only sent to the right, as in
+                        //   `Worker::point_to_point_sync`
+                        let (my_first, my_last) = match layers[index] {
+                            Some((first, last)) if first != last => {
+                                (Some(ptr(first)), Some(ptr(last)))
+                            }
+                            Some((_, last)) => (None, Some(ptr(last))),
+                            None => (None, None),
+                        };
+                        let expected_first = layers[index + 1..]
+                            .iter()
+                            .find_map(|layer| layer.filter(|(first, last)| first != last))
+                            .map_or(std::ptr::null_mut(), |(first, _)| ptr(first));
+                        let expected_last = layers[..index]
+                            .iter()
+                            .rev()
+                            .find_map(|layer| *layer)
+                            .map_or(std::ptr::null_mut(), |(_, last)| ptr(last));
+                        let (their_first, their_last) =
+                            handshake.exchange(index, round, my_first, my_last);
+                        assert_eq!(their_first, expected_first);
+                        assert_eq!(their_last, expected_last);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    });
+}
+
+#[test]
+fn loom_exchange() {
+    // every interleaving: two neighbours over three rounds, so that the
+    //   slots of round 1 are reused in round 3
+    check_exchange(&[Some((1, 2)), Some((3, 4))], 3, None);
+    // three threads, the middle one forwarding what it holds no node for;
+    //   unbounded, a single round does not finish within minutes, while 3
+    //   preemptions let each thread be interrupted once within the round
+    check_exchange(&[Some((1, 2)), None, Some((3, 4))], 1, Some(3));
+    check_exchange(&[Some((1, 2)), Some((3, 3)), Some((4, 5))], 1, Some(3));
+}
@@ -1,13 +1,19 @@
-use palm::palm::node::*;
-use palm::palm::nodeptr::*;
-use palm::palm::notthreadsafe::NotThreadSafe;
+#![cfg(not(feature = "loom"))]
+
+use palm::palm::invariants::InvariantError;
+use palm::palm::map::PalmMap;
+use palm::palm::node::{fanout_for, LeafLayout, DEFAULT_FANOUT};
 use palm::palm::query::*;
-use palm::palm::tree::*;
-use palm::palm::util::*;
-use palm::palm::worker::*;
+use palm::palm::stats::{Stats, FILL_BUCKETS};
+use palm::palm::util::{Kernels, SearchKey, SimdLevel, SortedSearch};
+use palm::palm::vector::MyVector;
+use palm::palm::wal::{LogFile, SyncPolicy};
+use palm::palm::worker::Partitioning;
 
 use rand::{thread_rng, Rng};
 use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 type KeyType = u32;
@@ -16,52 +22,83 @@ const NUM_BATCHES: usize = 512;
 const NUM_THREADS: usize = 8;
 const KEY_RANGE: KeyType = 10000;
 
-fn validate<K, V>(node: &mut Node<K, V>, l: &K, r: &K)
-where
-    K: Ord + Clone + std::fmt::Debug,
-    V: Clone + std::fmt::Debug,
-{
-    if node.is_leaf() {
-        for i in 0..node.keys.len() {
-            assert!(
-                l <= &node.keys[i] && &node.keys[i] < r,
-                "{:?} <= {:?}[{}] < {:?}",
-                l,
-                node.keys[i],
-                i,
-                r
-            );
+#[test]
+fn test_palm() {
+    let mut rng = thread_rng();
+
+    let mut tree = PalmMap::<KeyType, KeyType>::new(NUM_THREADS);
+    let mut map = BTreeMap::new();
+    for _ in 0..NUM_BATCHES {
+        let mut ref_result = vec![];
+        let mut batch = vec![];
+        for j in 0..BATCH_SIZE {
+            let k = rng.gen_range(0, KEY_RANGE);
+            let query = match j % 4 {
+                0 | 1 => Query::Insertion {
+                    k,
+                    v: rng.gen_range(0, KEY_RANGE),
+                },
+                2 => Query::Deletion { k },
+                _ => Query::Retrieval { k },
+            };
+            let response = match &query {
+                Query::Insertion { k, v } => map.insert(*k, *v),
+                Query::Deletion { k } => map.remove(k),
+                _ => map.get(&k).copied(),
+            };
+            ref_result.push((query.clone(), response.into()));
+            batch.push(query);
         }
-    } else {
-        let mut lo = l.clone();
-        for i in 0..node.keys.len() {
-            assert!(
-                l <= &node.keys[i] && &node.keys[i] < r,
-                "{:?} <= {:?}[{}] < {:?}",
-                l,
-                node.keys[i],
-                i,
-                r
-            );
-            assert!(node.ptrs()[i].get_mut().parent == NodePtr::new(node as *mut _));
-            validate(node.ptrs()[i].get_mut(), &lo, &node.keys[i]);
-            lo = node.keys[i].clone();
+
+        assert_eq!(tree.apply_batch(&batch), ref_result);
+        tree.check_invariants().unwrap();
+    }
+    assert!(tree.iter().map(|(k, v)| (*k, *v)).eq(map.into_iter()));
+}
+
+#[test]
+fn test_single_ops() {
+    let mut rng = thread_rng();
+
+    let mut tree = PalmMap::<KeyType, KeyType>::new(NUM_THREADS);
+    let mut map = BTreeMap::new();
+    for _ in 0..NUM_BATCHES {
+        let k = rng.gen_range(0, KEY_RANGE);
+        match rng.gen_range(0, 3) {
+            0 => {
+                let v = rng.gen_range(0, KEY_RANGE);
+                assert_eq!(tree.insert(k, v), map.insert(k, v));
+            }
+            1 => assert_eq!(tree.remove(&k), map.remove(&k)),
+            _ => assert_eq!(tree.get(&k), map.get(&k).cloned()),
         }
-        let idx = node.keys.len();
-        assert!(node.ptrs()[idx].get_mut().parent == NodePtr::new(node as *mut _));
-        validate(node.ptrs()[idx].get_mut(), &lo, r);
     }
+    tree.check_invariants().unwrap();
+
+    // between batches, any number of threads may read the tree
+    let (tree, map) = (&*tree, &map);
+    std::thread::scope(|s| {
+        for t in 0..4 {
+            s.spawn(move || {
+                for k in (t..KEY_RANGE).step_by(4) {
+                    assert_eq!(tree.get(&k), map.get(&k).cloned());
+                }
+                assert!(tree
+                    .range(t * 100..)
+                    .map(|(k, v)| (*k, *v))
+                    .eq(map.range(t * 100..).map(|(k, v)| (*k, *v))));
+            });
+        }
+    });
 }
 
 #[test]
-fn test_palm() {
+fn test_pool() {
     // let seed = [1u8; 32];
     // let mut rng : StdRng = SeedableRng::from_seed(seed);
     let mut rng = thread_rng();
 
-    let tree = Arc::new(NotThreadSafe::new(Palm::<KeyType, KeyType>::new(
-        NUM_THREADS,
-    )));
+    let mut tree = PalmMap::<KeyType, KeyType>::new(NUM_THREADS);
     let mut map = BTreeMap::new();
     for _ in 0..NUM_BATCHES {
         let mut ref_result = vec![];
@@ -69,71 +106,1405 @@ fn test_palm() {
         for j in 0..BATCH_SIZE {
             if j % 2 == 0 {
                 let (k, v) = (rng.gen_range(0, KEY_RANGE), rng.gen_range(0, KEY_RANGE));
-                let query = Query::Insertion {
-                    k: k.clone(),
-                    v: v.clone(),
-                };
+                let query = Query::Insertion { k, v };
                 batch.push(query.clone());
-                ref_result.push((query, map.insert(k, v)));
+                ref_result.push((query, map.insert(k, v).into()));
             } else {
                 let k = rng.gen_range(1, KEY_RANGE);
-                let query = Query::Retrieval { k: k.clone() };
+                let query = Query::Retrieval { k };
                 batch.push(query.clone());
-                ref_result.push((query, map.get(&k).map(|v| v.clone())));
+                ref_result.push((query, map.get(&k).copied().into()));
             }
         }
 
-        ref_result.sort_by_key(|p| p.0.clone());
-        let mut result = Palm::run_batch(&tree, &mut batch);
-        result.sort_by_key(|p| p.0.clone());
+        let result = tree.apply_batch(&batch);
 
         assert_eq!(ref_result.len(), result.len());
         for i in 0..ref_result.len() {
             assert_eq!(ref_result[i], result[i]);
         }
-        validate(tree.get().root.get_mut(), &0, &KEY_RANGE);
+        tree.check_invariants().unwrap();
     }
 }
 
 #[test]
-fn test_pool() {
-    // let seed = [1u8; 32];
-    // let mut rng : StdRng = SeedableRng::from_seed(seed);
+fn test_report() {
     let mut rng = thread_rng();
+    let mut tree = PalmMap::<KeyType, KeyType, 8>::new(NUM_THREADS);
+    assert!(tree.pool().report().threads.is_empty());
+    for i in 0..NUM_BATCHES / 32 {
+        let batch: Vec<_> = (0..BATCH_SIZE)
+            .map(|_| {
+                let k = rng.gen_range(0, KEY_RANGE);
+                match i % 2 {
+                    0 => Query::Insertion { k, v: k },
+                    _ => Query::Range {
+                        lo: k,
+                        hi: k + 10,
+                        limit: None,
+                    },
+                }
+            })
+            .collect();
+        let depth = tree.depth();
+        tree.apply_batch(&batch);
 
-    let tree = Arc::new(NotThreadSafe::new(Palm::<KeyType, KeyType>::new(
-        NUM_THREADS,
-    )));
-    let mut wrapper = PalmWrapper::new(tree.clone(), NUM_THREADS);
+        let report = tree.pool().report();
+        assert_eq!(report.threads.len(), NUM_THREADS);
+        for (t, thread) in report.threads.iter().enumerate() {
+            // one entry per level of the tree the batch started on
+            assert_eq!(thread.levels.len(), depth);
+            assert!(thread.busy() + thread.waiting() <= report.total);
+            if t > 0 {
+                assert_eq!(thread.root, std::time::Duration::ZERO);
+            }
+            if i % 2 == 0 {
+                assert_eq!(thread.scan, std::time::Duration::ZERO);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_oversubscribed() {
+    // far more workers than cores, so waiting neighbours have to park
+    let mut rng = thread_rng();
+    let mut tree = PalmMap::<KeyType, KeyType, 4>::new(64);
     let mut map = BTreeMap::new();
-    for _ in 0..NUM_BATCHES {
+    for _ in 0..16 {
+        let batch: Vec<_> = (0..BATCH_SIZE / 8)
+            .map(|_| {
+                let k = rng.gen_range(0, KEY_RANGE);
+                if rng.gen_range(0, 3) > 0 {
+                    map.insert(k, k);
+                    Query::Insertion { k, v: k }
+                } else {
+                    map.remove(&k);
+                    Query::Deletion { k }
+                }
+            })
+            .collect();
+        tree.apply_batch(&batch);
+        tree.check_invariants().unwrap();
+    }
+    assert!(tree.iter().map(|(k, v)| (*k, *v)).eq(map.into_iter()));
+}
+
+#[test]
+fn test_partitioning() {
+    let mut rng = thread_rng();
+    let mut tree = PalmMap::<KeyType, KeyType, 8>::new(NUM_THREADS);
+    assert_eq!(tree.partitioning(), Partitioning::Keys);
+    let mut map = BTreeMap::new();
+    for i in 0..NUM_BATCHES / 8 {
+        // switching back and forth, as the mode only matters within a batch
+        let partitioning = match i % 4 {
+            3 => Partitioning::Keys,
+            _ => Partitioning::Leaves,
+        };
+        tree.set_partitioning(partitioning);
+        let mut ref_result = vec![];
+        let mut batch = vec![];
+        for j in 0..BATCH_SIZE / 4 {
+            // skewed: the range is halved a random number of times, so the
+            //   few leaves near 0 get most of the batch
+            let halvings = rng.gen_range(0, 14);
+            let k = rng.gen_range(0, KEY_RANGE >> halvings);
+            let query = match j % 8 {
+                0..=3 => Query::Insertion { k, v: j as KeyType },
+                4..=5 => Query::Deletion { k },
+                6 => Query::Retrieval { k },
+                _ => Query::Range {
+                    lo: k,
+                    hi: k + 20,
+                    limit: None,
+                },
+            };
+            let response = match &query {
+                Query::Insertion { k, v } => map.insert(*k, *v).into(),
+                Query::Deletion { k } => map.remove(k).into(),
+                Query::Retrieval { k } => map.get(k).copied().into(),
+                // filled in below, once every write of the batch is known
+                _ => Response::Value(None),
+            };
+            ref_result.push((query.clone(), response));
+            batch.push(query);
+        }
+        for (query, response) in ref_result.iter_mut() {
+            if let Query::Range { lo, hi, .. } = query {
+                *response = Response::Range(map.range(*lo..*hi).map(|(k, v)| (*k, *v)).collect());
+            }
+        }
+
+        assert_eq!(tree.apply_batch(&batch), ref_result);
+        assert_eq!(tree.partitioning(), partitioning);
+        tree.check_invariants().unwrap();
+    }
+    assert!(tree.iter().map(|(k, v)| (*k, *v)).eq(map.into_iter()));
+}
+
+#[test]
+fn test_deletion() {
+    let mut rng = thread_rng();
+
+    let mut tree = PalmMap::<KeyType, KeyType>::new(NUM_THREADS);
+    let mut map = BTreeMap::new();
+    for i in 0..NUM_BATCHES / 8 {
         let mut ref_result = vec![];
         let mut batch = vec![];
         for j in 0..BATCH_SIZE {
-            if j % 2 == 0 {
-                let (k, v) = (rng.gen_range(0, KEY_RANGE), rng.gen_range(0, KEY_RANGE));
-                let query = Query::Insertion {
-                    k: k.clone(),
-                    v: v.clone(),
-                };
-                batch.push(query.clone());
-                ref_result.push((query, map.insert(k, v)));
-            } else {
-                let k = rng.gen_range(1, KEY_RANGE);
-                let query = Query::Retrieval { k: k.clone() };
-                batch.push(query.clone());
-                ref_result.push((query, map.get(&k).map(|v| v.clone())));
+            let k = rng.gen_range(0, KEY_RANGE);
+            // grow the tree in the first half, then shrink it
+            let query = match (j % 3, i < NUM_BATCHES / 16) {
+                (0, _) => {
+                    let v = rng.gen_range(0, KEY_RANGE);
+                    ref_result.push((Query::Insertion { k, v }, map.insert(k, v).into()));
+                    Query::Insertion { k, v }
+                }
+                (1, _) | (2, false) => {
+                    ref_result.push((Query::Deletion { k }, map.remove(&k).into()));
+                    Query::Deletion { k }
+                }
+                _ => {
+                    ref_result.push((Query::Retrieval { k }, map.get(&k).cloned().into()));
+                    Query::Retrieval { k }
+                }
+            };
+            batch.push(query);
+        }
+
+        let result = tree.apply_batch(&batch);
+
+        assert_eq!(ref_result.len(), result.len());
+        for i in 0..ref_result.len() {
+            assert_eq!(ref_result[i], result[i]);
+        }
+        tree.check_invariants().unwrap();
+    }
+
+    // removing every remaining key collapses the tree back to a single leaf
+    let batch: Vec<_> = map.keys().map(|k| Query::Deletion { k: *k }).collect();
+    let result = tree.apply_batch(&batch);
+    assert!(result.iter().all(|(_, v)| v != &Response::Value(None)));
+    assert_eq!(tree.depth(), 1);
+    assert!(result.iter().all(|(q, _)| tree.get(q.get_key()).is_none()));
+}
+
+fn check_range<const F: usize>(layout: LeafLayout, num_batches: usize) {
+    let mut rng = thread_rng();
+
+    let mut tree = PalmMap::<KeyType, KeyType, F>::with_layout(NUM_THREADS, layout);
+    let mut map = BTreeMap::new();
+    for _ in 0..num_batches {
+        let mut ref_result = vec![];
+        let mut batch = vec![];
+        for j in 0..BATCH_SIZE {
+            let k = rng.gen_range(0, KEY_RANGE);
+            let query = match j % 8 {
+                0..=3 => {
+                    let v = rng.gen_range(0, KEY_RANGE);
+                    ref_result.push((Query::Insertion { k, v }, map.insert(k, v).into()));
+                    Query::Insertion { k, v }
+                }
+                4..=5 => {
+                    ref_result.push((Query::Deletion { k }, map.remove(&k).into()));
+                    Query::Deletion { k }
+                }
+                _ => {
+                    let hi = k + rng.gen_range(0, KEY_RANGE / 50);
+                    let limit = if j % 16 == 7 { Some(10) } else { None };
+                    // filled in below, once every write of the batch is known
+                    ref_result.push((Query::Range { lo: k, hi, limit }, Response::Value(None)));
+                    Query::Range { lo: k, hi, limit }
+                }
+            };
+            batch.push(query);
+        }
+        for (query, response) in ref_result.iter_mut() {
+            if let Query::Range { lo, hi, limit } = query {
+                let found = map
+                    .range(*lo..*hi)
+                    .take(limit.unwrap_or(usize::MAX))
+                    .map(|(k, v)| (*k, *v))
+                    .collect();
+                *response = Response::Range(found);
             }
         }
 
-        ref_result.sort_by_key(|p| p.0.clone());
-        let mut result = wrapper.run_batch(&mut batch);
-        result.sort_by_key(|p| p.0.clone());
+        let result = tree.apply_batch(&batch);
 
         assert_eq!(ref_result.len(), result.len());
         for i in 0..ref_result.len() {
             assert_eq!(ref_result[i], result[i]);
         }
-        validate(tree.get().root.get_mut(), &0, &KEY_RANGE);
+        tree.check_invariants().unwrap();
+    }
+}
+
+#[test]
+fn test_range() {
+    check_range::<39>(LeafLayout::Sorted, NUM_BATCHES / 8);
+}
+
+#[test]
+fn test_range_unsorted() {
+    check_range::<39>(LeafLayout::Unsorted, NUM_BATCHES / 8);
+}
+
+fn check_neighbours<const F: usize>(layout: LeafLayout, num_batches: usize) {
+    use std::ops::Bound::{Excluded, Unbounded};
+    let mut rng = thread_rng();
+
+    let mut tree = PalmMap::<KeyType, KeyType, F>::with_layout(NUM_THREADS, layout);
+    let mut map = BTreeMap::new();
+    for i in 0..num_batches {
+        let mut ref_result = vec![];
+        let mut batch = vec![];
+        for j in 0..BATCH_SIZE {
+            let k = rng.gen_range(0, KEY_RANGE);
+            // grow, then delete most keys so that whole leaves run empty
+            let query = match (j % 4, i < num_batches / 2) {
+                (0, true) | (1, true) => {
+                    let v = rng.gen_range(0, KEY_RANGE);
+                    ref_result.push((Query::Insertion { k, v }, map.insert(k, v).into()));
+                    Query::Insertion { k, v }
+                }
+                (0, false) | (1, false) => {
+                    ref_result.push((Query::Deletion { k }, map.remove(&k).into()));
+                    Query::Deletion { k }
+                }
+                (2, _) if j % 8 == 2 => Query::Floor { k },
+                (2, _) => Query::Predecessor { k },
+                _ if j % 8 == 3 => Query::Ceiling { k },
+                _ => Query::Successor { k },
+            };
+            if query.is_scan() {
+                // filled in below, once every write of the batch is known
+                ref_result.push((query.clone(), Response::Value(None)));
+            }
+            batch.push(query);
+        }
+        for (query, response) in ref_result.iter_mut() {
+            let found = match query {
+                Query::Floor { k } => map.range(..=*k).next_back(),
+                Query::Predecessor { k } => map.range(..*k).next_back(),
+                Query::Ceiling { k } => map.range(*k..).next(),
+                Query::Successor { k } => map.range((Excluded(*k), Unbounded)).next(),
+                _ => continue,
+            };
+            *response = Response::Entry(found.map(|(k, v)| (*k, *v)));
+        }
+
+        let result = tree.apply_batch(&batch);
+
+        assert_eq!(ref_result, result);
+        tree.check_invariants().unwrap();
+    }
+}
+
+#[test]
+fn test_neighbours() {
+    check_neighbours::<39>(LeafLayout::Sorted, NUM_BATCHES / 32);
+    check_neighbours::<8>(LeafLayout::Unsorted, NUM_BATCHES / 32);
+    check_neighbours::<4>(LeafLayout::Sorted, NUM_BATCHES / 32);
+}
+
+fn check_iter<const F: usize>(layout: LeafLayout) {
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+    let mut rng = thread_rng();
+
+    let mut tree = PalmMap::<KeyType, KeyType, F>::with_layout(NUM_THREADS, layout);
+    assert_eq!(tree.iter().next(), None);
+    let mut map = BTreeMap::new();
+    for _ in 0..4 {
+        let batch: Vec<_> = (0..BATCH_SIZE)
+            .map(|j| {
+                let k = rng.gen_range(0, KEY_RANGE);
+                if j % 3 == 0 {
+                    map.remove(&k);
+                    Query::Deletion { k }
+                } else {
+                    let v = rng.gen_range(0, KEY_RANGE);
+                    map.insert(k, v);
+                    Query::Insertion { k, v }
+                }
+            })
+            .collect();
+        tree.apply_batch(&batch);
+
+        assert!(tree.iter().eq(map.iter()));
+        assert!(tree.iter().rev().eq(map.iter().rev()));
+        assert!(tree.keys().eq(map.keys()));
+        assert!(tree.values().rev().eq(map.values().rev()));
+        assert!((&tree).into_iter().eq(&map));
+        for _ in 0..100 {
+            let lo = rng.gen_range(0, KEY_RANGE);
+            let hi = lo + rng.gen_range(0, KEY_RANGE / 10);
+            assert!(tree.range(lo..hi).eq(map.range(lo..hi)));
+            assert!(tree.range(lo..=hi).rev().eq(map.range(lo..=hi).rev()));
+            assert!(tree.range(..hi).eq(map.range(..hi)));
+            assert!(tree.range(lo..).eq(map.range(lo..)));
+            let bounds = (Excluded(lo), Included(hi));
+            assert!(tree.range(bounds).eq(map.range(bounds)));
+            assert!(tree
+                .range((Excluded(hi), Unbounded))
+                .eq(map.range((Excluded(hi), Unbounded))));
+        }
+
+        // both ends meeting in the middle, possibly within a single leaf
+        let lo = rng.gen_range(0, KEY_RANGE / 2);
+        let (mut iter, mut expected) = if rng.gen::<bool>() {
+            (tree.iter(), map.range(..))
+        } else {
+            (tree.range(lo..lo * 2), map.range(lo..lo * 2))
+        };
+        loop {
+            let (a, b) = if rng.gen::<bool>() {
+                (iter.next(), expected.next())
+            } else {
+                (iter.next_back(), expected.next_back())
+            };
+            assert_eq!(a, b);
+            if a.is_none() {
+                break;
+            }
+        }
+    }
+    assert!(tree.into_iter().rev().eq(map.into_iter().rev()));
+}
+
+#[test]
+fn test_iter() {
+    check_iter::<39>(LeafLayout::Sorted);
+    check_iter::<8>(LeafLayout::Unsorted);
+    check_iter::<4>(LeafLayout::Sorted);
+}
+
+fn check_bulk_load<const F: usize>(fill_factor: f64) {
+    let mut rng = thread_rng();
+
+    // even keys first, then odd ones and every fourth even one again
+    let mut tree = PalmMap::<KeyType, KeyType, F>::new(NUM_THREADS);
+    let mut map: BTreeMap<_, _> = (0..KEY_RANGE * 10).step_by(2).map(|k| (k, k)).collect();
+    tree.bulk_load(map.clone(), fill_factor);
+    tree.check_invariants().unwrap();
+    assert!(tree.iter().eq(map.iter()));
+
+    let run: Vec<_> = (0..KEY_RANGE * 10)
+        .filter(|k| k % 2 == 1 || k % 8 == 0)
+        .map(|k| (k, k + 1))
+        .collect();
+    map.extend(run.iter().cloned());
+    tree.bulk_merge(run, fill_factor);
+    tree.check_invariants().unwrap();
+    assert!(tree.iter().eq(map.iter()));
+
+    // batches still split and merge the loaded nodes correctly
+    for _ in 0..4 {
+        let mut ref_result = vec![];
+        let mut batch = vec![];
+        for j in 0..BATCH_SIZE {
+            let k = rng.gen_range(0, KEY_RANGE * 10);
+            if j % 2 == 0 {
+                ref_result.push((Query::Deletion { k }, map.remove(&k).into()));
+                batch.push(Query::Deletion { k });
+            } else {
+                ref_result.push((Query::Insertion { k, v: k }, map.insert(k, k).into()));
+                batch.push(Query::Insertion { k, v: k });
+            }
+        }
+        assert_eq!(tree.apply_batch(&batch), ref_result);
+        tree.check_invariants().unwrap();
+    }
+    assert!(tree.iter().eq(map.iter()));
+}
+
+#[test]
+fn test_bulk_load() {
+    check_bulk_load::<4>(1.0);
+    check_bulk_load::<5>(0.5);
+    check_bulk_load::<39>(0.7);
+    check_bulk_load::<128>(0.01);
+
+    // an empty load, and a merge into an empty tree
+    let mut tree = PalmMap::<KeyType, KeyType, 8>::new(NUM_THREADS);
+    tree.bulk_load(vec![], 1.0);
+    assert_eq!(tree.depth(), 1);
+    tree.bulk_merge(vec![(1, 1), (2, 2)], 1.0);
+    assert!(tree.iter().eq([(&1, &1), (&2, &2)]));
+    assert_eq!(tree.depth(), 1);
+
+    // 4 entries per leaf and 5 children per node: 64 leaves, 13, 3 and a root
+    let mut tree = PalmMap::<KeyType, KeyType, 8>::new(NUM_THREADS);
+    tree.bulk_load((0..4 * 4 * 4 * 4).map(|k| (k, k)), 4.0 / 7.0);
+    tree.check_invariants().unwrap();
+    assert_eq!(tree.depth(), 4);
+}
+
+#[test]
+#[should_panic(expected = "keys must be strictly increasing")]
+fn test_bulk_load_unsorted() {
+    let mut tree = PalmMap::<KeyType, KeyType>::new(NUM_THREADS);
+    tree.bulk_load(vec![(2, 2), (1, 1)], 1.0);
+}
+
+#[test]
+fn test_snapshot() {
+    let mut rng = thread_rng();
+
+    // the same pairs, inserted in different orders and through different fanouts
+    let pairs: Vec<_> = (0..KEY_RANGE)
+        .map(|k| (k * 3, rng.gen_range(0, KEY_RANGE)))
+        .collect();
+    let mut tree = PalmMap::<KeyType, KeyType>::new(NUM_THREADS);
+    let batch: Vec<_> = pairs
+        .iter()
+        .map(|&(k, v)| Query::Insertion { k, v })
+        .collect();
+    tree.apply_batch(&batch);
+    let mut other = PalmMap::<KeyType, KeyType, 8>::with_layout(NUM_THREADS, LeafLayout::Unsorted);
+    for chunk in batch.chunks(1000).rev() {
+        other.apply_batch(chunk);
     }
+
+    let mut snapshot = Vec::new();
+    tree.save_snapshot(&mut snapshot).unwrap();
+    let mut restored = PalmMap::<KeyType, KeyType, 8>::new(NUM_THREADS);
+    restored.insert(1, 1);
+    restored.load_snapshot(&snapshot[..]).unwrap();
+    restored.check_invariants().unwrap();
+    assert!(restored.iter().eq(tree.iter()));
+
+    // restored trees only depend on their contents
+    let mut a = Vec::new();
+    restored.save_snapshot(&mut a).unwrap();
+    let mut b = Vec::new();
+    other.save_snapshot(&mut b).unwrap();
+    restored.load_snapshot(&b[..]).unwrap();
+    let mut c = Vec::new();
+    restored.save_snapshot(&mut c).unwrap();
+    assert_eq!(a, c);
+
+    // any damage is detected
+    for _ in 0..100 {
+        let mut damaged = snapshot.clone();
+        let i = rng.gen_range(0, damaged.len());
+        damaged[i] ^= 1 << rng.gen_range(0, 8);
+        assert!(restored.load_snapshot(&damaged[..]).is_err());
+    }
+    let truncated = &snapshot[..snapshot.len() - 1];
+    assert!(restored.load_snapshot(truncated).is_err());
+    let mut wrong = PalmMap::<KeyType, u64>::new(NUM_THREADS);
+    let error = wrong.load_snapshot(&snapshot[..]).unwrap_err();
+    assert_eq!(error.to_string(), "snapshot holds u32, not u64");
+
+    // variable-length keys, and an empty tree
+    let mut strings = PalmMap::<String, Vec<u8>, 8>::new(NUM_THREADS);
+    let batch: Vec<_> = (0..1000)
+        .map(|i| Query::Insertion {
+            k: byte_key(i),
+            v: byte_key(i * 7),
+        })
+        .collect();
+    strings.apply_batch(&batch);
+    let mut snapshot = Vec::new();
+    strings.save_snapshot(&mut snapshot).unwrap();
+    let mut restored = PalmMap::<String, Vec<u8>>::new(NUM_THREADS);
+    restored.load_snapshot(&snapshot[..]).unwrap();
+    assert!(restored.iter().eq(strings.iter()));
+    let mut snapshot = Vec::new();
+    PalmMap::<String, Vec<u8>>::new(NUM_THREADS)
+        .save_snapshot(&mut snapshot)
+        .unwrap();
+    restored.load_snapshot(&snapshot[..]).unwrap();
+    assert_eq!(restored.iter().count(), 0);
+}
+
+fn wal_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("palm-{}-{}.wal", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn test_wal() {
+    let mut rng = thread_rng();
+    let merge = |v: &mut KeyType, op: &KeyType| *v = v.wrapping_mul(31).wrapping_add(*op);
+    let path = wal_path("test_wal");
+    // a small pool, as the test recovers about a hundred times
+    let recover = |sync| {
+        let mut tree = PalmMap::<KeyType, KeyType>::new(2);
+        tree.set_merge_operator(merge);
+        let replayed = tree.open_wal(&path, sync).unwrap();
+        (tree, replayed)
+    };
+    let contents =
+        |tree: &PalmMap<KeyType, KeyType>| tree.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+
+    // every kind of write, with the contents and the log length after each batch
+    let (mut tree, replayed) = recover(SyncPolicy::EveryBatch);
+    assert_eq!(replayed, 0);
+    let mut batches = vec![];
+    let mut states = vec![contents(&tree)];
+    let mut lens = vec![std::fs::metadata(&path).unwrap().len()];
+    for _ in 0..8 {
+        let batch: Vec<_> = (0..200)
+            .map(|j| {
+                let k = rng.gen_range(0, 500);
+                let v = rng.gen_range(0, KEY_RANGE);
+                match j % 6 {
+                    0 => Query::Insertion { k, v },
+                    1 => Query::Deletion { k },
+                    2 => Query::Update { k, op: v },
+                    3 => Query::InsertIfAbsent { k, v },
+                    4 => Query::CompareAndSwap {
+                        k,
+                        expected: tree.get(&k).unwrap_or(v),
+                        new: v,
+                    },
+                    _ => Query::Retrieval { k },
+                }
+            })
+            .collect();
+        tree.apply_batch(&batch);
+        batches.push(batch);
+        states.push(contents(&tree));
+        lens.push(std::fs::metadata(&path).unwrap().len());
+    }
+    // batches without writes are not logged
+    tree.apply_batch(&[Query::Retrieval { k: 1 }]);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), lens[8]);
+    drop(tree);
+
+    let (tree, replayed) = recover(SyncPolicy::Never);
+    assert_eq!(replayed, 8);
+    assert_eq!(contents(&tree), states[8]);
+    drop(tree);
+
+    // a crash tears the last record; recovery drops it, and logging resumes
+    let full = std::fs::read(&path).unwrap();
+    for i in 1..=8 {
+        let (start, end) = (lens[i - 1] as usize, lens[i] as usize);
+        for cut in [
+            start + 1,
+            start + 4,
+            start + 8,
+            start + 12,
+            start + 13,
+            (start + end) / 2,
+            end - 1,
+        ] {
+            std::fs::write(&path, &full[..cut]).unwrap();
+            let (mut tree, replayed) = recover(SyncPolicy::EveryBatch);
+            assert_eq!(replayed, i - 1);
+            assert_eq!(contents(&tree), states[i - 1]);
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), lens[i - 1]);
+            tree.apply_batch(&batches[i - 1]);
+            drop(tree);
+            let (tree, replayed) = recover(SyncPolicy::EveryBatch);
+            assert_eq!(replayed, i);
+            assert_eq!(contents(&tree), states[i]);
+        }
+    }
+    // ... as well as the header, or leave a zeroed tail behind
+    std::fs::write(&path, &full[..5]).unwrap();
+    assert_eq!(recover(SyncPolicy::EveryBatch).1, 0);
+    let mut zeroed = full.clone();
+    zeroed.resize(full.len() + 100, 0);
+    std::fs::write(&path, &zeroed).unwrap();
+    assert_eq!(recover(SyncPolicy::EveryBatch).1, 8);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), lens[8]);
+
+    // damage before the last record is not mistaken for a crash
+    let mut damaged = full.clone();
+    damaged[lens[2] as usize + 20] ^= 1;
+    std::fs::write(&path, &damaged).unwrap();
+    let mut tree = PalmMap::<KeyType, KeyType>::new(NUM_THREADS);
+    tree.set_merge_operator(merge);
+    let error = tree.open_wal(&path, SyncPolicy::EveryBatch).unwrap_err();
+    assert_eq!(error.to_string(), "write-ahead log is corrupted");
+    // ... nor is a length running past the end of the file
+    let mut damaged = full.clone();
+    damaged[lens[2] as usize + 3] = 0xff;
+    std::fs::write(&path, &damaged).unwrap();
+    let error = tree.open_wal(&path, SyncPolicy::EveryBatch).unwrap_err();
+    assert_eq!(error.to_string(), "write-ahead log is corrupted");
+    std::fs::write(&path, &full).unwrap();
+    let mut wrong = PalmMap::<KeyType, u64>::new(NUM_THREADS);
+    let error = wrong.open_wal(&path, SyncPolicy::EveryBatch).unwrap_err();
+    assert_eq!(error.to_string(), "log holds u32, not u64");
+
+    // recovery replays the log on top of the last snapshot
+    let (mut tree, _) = recover(SyncPolicy::EveryBatch);
+    let mut snapshot = Vec::new();
+    tree.save_snapshot(&mut snapshot).unwrap();
+    tree.truncate_wal().unwrap();
+    tree.apply_batch(&batches[0]);
+    let expected = contents(&tree);
+    drop(tree);
+    let mut tree = PalmMap::<KeyType, KeyType>::new(NUM_THREADS);
+    tree.set_merge_operator(merge);
+    tree.load_snapshot(&snapshot[..]).unwrap();
+    assert_eq!(tree.open_wal(&path, SyncPolicy::EveryBatch).unwrap(), 1);
+    assert_eq!(contents(&tree), expected);
+    std::fs::remove_file(&path).unwrap();
+}
+
+// A log file whose writes fail once `budget` bytes are written, and whose
+//   syncs fail while `fail_sync` is set
+struct FailingFile {
+    file: std::fs::File,
+    budget: Arc<AtomicUsize>,
+    fail_sync: Arc<AtomicBool>,
+}
+
+impl Read for FailingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for FailingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.budget.load(Ordering::Relaxed));
+        if len == 0 && !buf.is_empty() {
+            return Err(io::Error::other("injected write error"));
+        }
+        let len = self.file.write(&buf[..len])?;
+        self.budget.fetch_sub(len, Ordering::Relaxed);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FailingFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl LogFile for FailingFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        if self.fail_sync.load(Ordering::Relaxed) {
+            return Err(io::Error::other("injected sync error"));
+        }
+        self.file.sync_data()
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+#[test]
+fn test_wal_failures() {
+    let path = wal_path("test_wal_failures");
+    let budget = Arc::new(AtomicUsize::new(usize::MAX));
+    let fail_sync = Arc::new(AtomicBool::new(false));
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .unwrap();
+    let file = FailingFile {
+        file,
+        budget: budget.clone(),
+        fail_sync: fail_sync.clone(),
+    };
+    let mut tree = PalmMap::<KeyType, KeyType>::new(2);
+    assert_eq!(tree.open_wal_file(file, SyncPolicy::EveryBatch).unwrap(), 0);
+    let batch = |i: KeyType| -> Vec<_> {
+        (0..100)
+            .map(|k| Query::Insertion { k: k * 8 + i, v: i })
+            .collect()
+    };
+    let len = || std::fs::metadata(&path).unwrap().len();
+    tree.apply_batch(&batch(0));
+
+    // a write cut short leaves no partial record behind
+    let before = len();
+    budget.store(10, Ordering::Relaxed);
+    let error = tree.try_apply_batch(&batch(1)).unwrap_err();
+    assert_eq!(error.to_string(), "injected write error");
+    assert_eq!(len(), before);
+    budget.store(usize::MAX, Ordering::Relaxed);
+    tree.apply_batch(&batch(2));
+
+    // a record that could not be synced is taken back, as its batch is not
+    //   applied
+    let before = len();
+    fail_sync.store(true, Ordering::Relaxed);
+    let error = tree.try_apply_batch(&batch(3)).unwrap_err();
+    assert_eq!(error.to_string(), "injected sync error");
+    assert_eq!(len(), before);
+    fail_sync.store(false, Ordering::Relaxed);
+    tree.apply_batch(&batch(4));
+    assert_eq!(tree.get(&1), None);
+    assert_eq!(tree.get(&3), None);
+    let expected: Vec<_> = tree.iter().map(|(k, v)| (*k, *v)).collect();
+    drop(tree);
+
+    // later records are still readable
+    let mut tree = PalmMap::<KeyType, KeyType>::new(2);
+    assert_eq!(tree.open_wal(&path, SyncPolicy::EveryBatch).unwrap(), 3);
+    assert!(tree.iter().map(|(k, v)| (*k, *v)).eq(expected.into_iter()));
+    std::fs::remove_file(&path).unwrap();
+}
+
+// Properties any tree's statistics have
+fn check_stats(stats: &Stats, len: usize) {
+    assert_eq!(stats.keys, len);
+    assert_eq!(stats.levels.len(), stats.depth);
+    assert_eq!(stats.levels.last().unwrap().nodes, 1);
+    for (i, level) in stats.levels.iter().enumerate() {
+        assert_eq!(level.fill.iter().sum::<usize>(), level.nodes);
+        assert!(level.min_len as f64 <= level.avg_len && level.avg_len <= level.max_len as f64);
+        assert!(level.max_len <= stats.max_len);
+        if i + 1 < stats.depth {
+            assert_eq!(level.underfull, 0);
+            assert!(level.min_len >= stats.min_len);
+        }
+        // an internal node separates its children with one key less than it has
+        if i > 0 {
+            assert_eq!(level.keys, stats.levels[i - 1].nodes - level.nodes);
+        }
+    }
+    let nodes = stats.leaves() + stats.internal_nodes();
+    assert_eq!(stats.bytes, nodes * stats.node_size);
+}
+
+#[test]
+fn test_stats() {
+    let mut rng = thread_rng();
+
+    let mut tree = PalmMap::<KeyType, KeyType, 8>::new(NUM_THREADS);
+    let stats = tree.stats();
+    check_stats(&stats, 0);
+    assert_eq!(
+        (stats.depth, stats.leaves(), stats.internal_nodes()),
+        (1, 1, 0)
+    );
+    assert_eq!((stats.min_len, stats.max_len), (3, 7));
+    assert_eq!(stats.levels[0].underfull, 1);
+
+    // full leaves go to the last bucket
+    tree.bulk_load((0..7000).map(|k| (k, k)), 1.0);
+    let stats = tree.stats();
+    check_stats(&stats, 7000);
+    assert_eq!(stats.leaves(), 1000);
+    assert_eq!(stats.levels[0].fill[FILL_BUCKETS - 1], 1000);
+    assert_eq!(stats.levels[0].avg_len, 7.0);
+
+    let mut map: BTreeMap<_, _> = (0..7000).map(|k| (k, k)).collect();
+    for _ in 0..NUM_BATCHES / 32 {
+        let batch: Vec<_> = (0..BATCH_SIZE)
+            .map(|_| {
+                let k = rng.gen_range(0, KEY_RANGE);
+                if rng.gen::<bool>() {
+                    map.insert(k, k);
+                    Query::Insertion { k, v: k }
+                } else {
+                    map.remove(&k);
+                    Query::Deletion { k }
+                }
+            })
+            .collect();
+        tree.apply_batch(&batch);
+        check_stats(&tree.stats(), map.len());
+    }
+}
+
+#[test]
+fn test_invariants() {
+    // trees of every shape a caller can build hold up
+    for layout in [LeafLayout::Sorted, LeafLayout::Unsorted] {
+        let mut tree = PalmMap::<KeyType, KeyType, 4>::with_layout(NUM_THREADS, layout);
+        tree.check_invariants().unwrap();
+        tree.bulk_load((0..1000).map(|k| (k, k)), 1.0);
+        tree.check_invariants().unwrap();
+        // down to a few keys, the root shrinks back to a single leaf
+        let batch: Vec<_> = (2..1000).map(|k| Query::Deletion { k }).collect();
+        tree.apply_batch(&batch);
+        tree.check_invariants().unwrap();
+        assert_eq!(tree.stats().depth, 1);
+        let batch: Vec<_> = (0..1000).rev().map(|k| Query::Deletion { k }).collect();
+        tree.apply_batch(&batch);
+        tree.check_invariants().unwrap();
+    }
+
+    let error = InvariantError::SeparatorBounds {
+        path: vec![0, 3],
+        key: 7,
+        lo: Some(8),
+        hi: None,
+    };
+    assert_eq!(error.to_string(), "7 at [0, 3] is outside [Some(8), None)");
+    let error: Box<dyn std::error::Error> = Box::new(InvariantError::<KeyType>::Depth {
+        depth: 2,
+        height: 3,
+    });
+    assert_eq!(error.to_string(), "depth is 2, but the tree is 3 high");
+    let error = InvariantError::<KeyType>::KeylessRoot { level: 2 };
+    assert_eq!(error.to_string(), "internal root at level 2 has no keys");
+}
+
+#[test]
+fn test_fanout() {
+    // tiny nodes split and merge on almost every batch
+    check_range::<4>(LeafLayout::Sorted, NUM_BATCHES / 32);
+    check_range::<5>(LeafLayout::Unsorted, NUM_BATCHES / 32);
+    check_range::<8>(LeafLayout::Sorted, NUM_BATCHES / 32);
+    check_range::<128>(LeafLayout::Sorted, NUM_BATCHES / 32);
+    assert_eq!(fanout_for::<i32, i32>(), DEFAULT_FANOUT);
+    assert_eq!(fanout_for::<u64, [u8; 64]>(), 6);
+
+    // the largest fanout within 8 cache lines
+    assert!(node_size::<i32, i32, DEFAULT_FANOUT>() <= 512);
+    assert!(node_size::<i32, i32, { DEFAULT_FANOUT + 1 }>() > 512);
+    assert!(node_size::<u64, [u8; 64], { fanout_for::<u64, [u8; 64]>() }>() <= 512);
+    assert!(node_size::<u64, [u8; 64], { fanout_for::<u64, [u8; 64]>() + 1 }>() > 512);
+    assert!(node_size::<u8, u16, { fanout_for::<u8, u16>() }>() <= 512);
+    assert!(node_size::<u8, u16, { fanout_for::<u8, u16>() + 1 }>() > 512);
+    assert!(node_size::<String, Vec<u8>, { fanout_for::<String, Vec<u8>>() }>() <= 512);
+    assert!(node_size::<String, Vec<u8>, { fanout_for::<String, Vec<u8>>() + 1 }>() > 512);
+}
+
+fn node_size<K, V, const F: usize>() -> usize
+where
+    K: 'static + SearchKey + Clone + std::fmt::Debug + Sync + Send,
+    V: 'static + Clone + PartialEq + std::fmt::Debug + Sync + Send,
+{
+    PalmMap::<K, V, F>::new(1).stats().node_size
+}
+
+#[test]
+fn test_submission_order() {
+    let mut tree = PalmMap::<KeyType, KeyType>::new(NUM_THREADS);
+
+    // descending keys with repeats: responses to the same key can only be
+    //   told apart by their position
+    let batch: Vec<_> = (0..BATCH_SIZE as KeyType)
+        .rev()
+        .map(|i| Query::Insertion { k: i / 4, v: i })
+        .collect();
+    let result = tree.apply_batch(&batch);
+    for (i, (query, response)) in result.iter().enumerate() {
+        let v = (BATCH_SIZE - 1 - i) as KeyType;
+        assert_eq!(query, &batch[i]);
+        let prev = if v % 4 == 3 { None } else { Some(v + 1) };
+        assert_eq!(response, &Response::Value(prev));
+    }
+
+    // a single hot key: the whole batch lands on one thread after sorting
+    let batch: Vec<_> = (0..BATCH_SIZE as KeyType)
+        .map(|i| Query::Insertion { k: KEY_RANGE, v: i })
+        .collect();
+    let result = tree.apply_batch(&batch);
+    assert_eq!(result[0].1, Response::Value(None));
+    for (i, (_, response)) in result.iter().enumerate().skip(1) {
+        assert_eq!(response, &Response::Value(Some(i as KeyType - 1)));
+    }
+    tree.check_invariants().unwrap();
+
+    // fewer queries than threads, and an empty batch
+    let batch = vec![Query::Retrieval { k: 0 }, Query::Retrieval { k: 1 }];
+    let result = tree.apply_batch(&batch);
+    assert_eq!(result[0].1, Response::Value(Some(0)));
+    assert_eq!(result[1].1, Response::Value(Some(4)));
+    assert!(tree.apply_batch(&[]).is_empty());
+}
+
+fn check_kernels<K>(candidates: &[K])
+where
+    K: SearchKey + Copy + std::fmt::Debug,
+{
+    let mut rng = thread_rng();
+    let scalar = Kernels::<K>::scalar();
+    for level in [SimdLevel::Scalar, SimdLevel::Sse42, SimdLevel::Avx2] {
+        if level > SimdLevel::detect() {
+            continue;
+        }
+        let kernels = K::kernels(level);
+        assert_eq!(kernels.level(), level);
+        for len in 0..=40 {
+            let mut keys: Vec<K> = (0..len)
+                .map(|_| candidates[rng.gen_range(0, candidates.len())])
+                .collect();
+            for value in candidates {
+                assert_eq!(
+                    kernels.linear_search(&keys, value),
+                    scalar.linear_search(&keys, value)
+                );
+            }
+            keys.sort();
+            for value in candidates {
+                assert_eq!(
+                    kernels.lower_bound(&keys, value),
+                    scalar.lower_bound(&keys, value),
+                    "{:?} in {:?}",
+                    value,
+                    keys
+                );
+                assert_eq!(
+                    kernels.upper_bound(&keys, value),
+                    scalar.upper_bound(&keys, value)
+                );
+            }
+        }
+    }
+}
+
+// values around both ends of the range and around zero, so that keys with
+//   and without the sign bit set are mixed
+macro_rules! candidates {
+    ($t:ty) => {
+        (0..8)
+            .flat_map(|i| {
+                vec![
+                    <$t>::MIN.wrapping_add(i),
+                    <$t>::MAX.wrapping_sub(i),
+                    (0 as $t).wrapping_add(i),
+                    (0 as $t).wrapping_sub(i),
+                ]
+            })
+            .collect::<Vec<$t>>()
+    };
+}
+
+#[test]
+fn test_simd_kernels() {
+    check_kernels(&candidates!(i8));
+    check_kernels(&candidates!(u8));
+    check_kernels(&candidates!(i16));
+    check_kernels(&candidates!(u16));
+    check_kernels(&candidates!(i32));
+    check_kernels(&candidates!(u32));
+    check_kernels(&candidates!(i64));
+    check_kernels(&candidates!(u64));
+}
+
+// A value that counts its live copies: `live()` is the number of clones
+//   of `counter` alive besides the test's own
+#[derive(Clone, Debug)]
+struct Tracked {
+    v: KeyType,
+    _counter: Arc<()>,
+}
+
+impl PartialEq for Tracked {
+    fn eq(&self, other: &Self) -> bool {
+        self.v == other.v
+    }
+}
+
+fn live(counter: &Arc<()>) -> usize {
+    Arc::strong_count(counter) - 1
+}
+
+#[test]
+fn test_vector_drop() {
+    let counter = Arc::new(());
+    let tracked = |v| Tracked {
+        v,
+        _counter: counter.clone(),
+    };
+    {
+        let mut vec = MyVector::<Tracked, 8>::new();
+        for i in 0..6 {
+            vec.push(tracked(i));
+        }
+        vec.insert(0, tracked(6));
+        vec.insert(3, tracked(7));
+        assert_eq!(
+            vec.iter().map(|t| t.v).collect::<Vec<_>>(),
+            [6, 0, 1, 7, 2, 3, 4, 5]
+        );
+        assert_eq!(vec.remove(1).v, 0);
+        assert_eq!(vec.pop().unwrap().v, 5);
+        assert_eq!(live(&counter), 6);
+
+        let right = vec.split_off(2);
+        assert_eq!(right.iter().map(|t| t.v).collect::<Vec<_>>(), [3, 4]);
+        let copy = right.clone();
+        assert_eq!(live(&counter), 8);
+
+        let mut drained: Vec<_> = copy.into();
+        assert_eq!(live(&counter), 8);
+        vec.clone_from_vec(&mut drained);
+        assert_eq!(vec.iter().map(|t| t.v).collect::<Vec<_>>(), [3, 4]);
+        assert_eq!(live(&counter), 4);
+
+        let mut back = MyVector::<Tracked, 8>::from(Vec::from(right));
+        assert_eq!(live(&counter), 4);
+        back.clear();
+        assert_eq!(live(&counter), 2);
+    }
+    assert_eq!(live(&counter), 0);
+}
+
+#[test]
+fn test_value_drop() {
+    let mut rng = thread_rng();
+    let counter = Arc::new(());
+    let tracked = |v| Tracked {
+        v,
+        _counter: counter.clone(),
+    };
+    {
+        let mut tree = PalmMap::<KeyType, Tracked, 8>::new(NUM_THREADS);
+        let mut map = BTreeMap::new();
+        for _ in 0..NUM_BATCHES / 32 {
+            let mut batch = vec![];
+            for j in 0..BATCH_SIZE / 4 {
+                let k = rng.gen_range(0, KEY_RANGE / 10);
+                if j % 3 == 0 {
+                    map.remove(&k);
+                    batch.push(Query::Deletion { k });
+                } else {
+                    let v = tracked(rng.gen_range(0, KEY_RANGE));
+                    map.insert(k, v.clone());
+                    batch.push(Query::Insertion { k, v });
+                }
+            }
+            tree.apply_batch(&batch);
+            drop(batch);
+            // one copy in the tree, one in the reference map
+            assert_eq!(live(&counter), 2 * map.len());
+        }
+        drop(map);
+        assert!(live(&counter) > 0);
+    }
+    assert_eq!(live(&counter), 0);
+}
+
+#[test]
+fn test_update() {
+    let mut rng = thread_rng();
+    // not commutative, so updates to one key must apply in submission order
+    let merge = |v: &mut KeyType, op: &KeyType| *v = v.wrapping_mul(31).wrapping_add(*op);
+
+    let mut tree = PalmMap::<KeyType, KeyType>::new(NUM_THREADS);
+    tree.set_merge_operator(merge);
+    let mut map = BTreeMap::new();
+    for _ in 0..NUM_BATCHES / 16 {
+        let mut ref_result = vec![];
+        let mut batch = vec![];
+        for j in 0..BATCH_SIZE {
+            // a narrow key range, so that most updates share their key
+            let k = rng.gen_range(0, KEY_RANGE / 100);
+            let query = match j % 8 {
+                0 => {
+                    let v = rng.gen_range(0, KEY_RANGE);
+                    ref_result.push((Query::Insertion { k, v }, map.insert(k, v).into()));
+                    Query::Insertion { k, v }
+                }
+                1 => {
+                    ref_result.push((Query::Deletion { k }, map.remove(&k).into()));
+                    Query::Deletion { k }
+                }
+                2 => {
+                    ref_result.push((Query::Retrieval { k }, map.get(&k).cloned().into()));
+                    Query::Retrieval { k }
+                }
+                _ => {
+                    let op = rng.gen_range(0, KEY_RANGE);
+                    let v = map.entry(k).or_insert(0);
+                    merge(v, &op);
+                    ref_result.push((Query::Update { k, op }, Some(*v).into()));
+                    Query::Update { k, op }
+                }
+            };
+            batch.push(query);
+        }
+
+        let result = tree.apply_batch(&batch);
+
+        assert_eq!(ref_result, result);
+        tree.check_invariants().unwrap();
+    }
+
+    let mut counters = PalmMap::<KeyType, u64>::new(NUM_THREADS);
+    counters.set_merge_operator(|v: &mut u64, op: &u64| *v += op);
+    let batch: Vec<_> = (0..BATCH_SIZE as KeyType)
+        .map(|i| Query::Update { k: i % 3, op: 5 })
+        .collect();
+    counters.apply_batch(&batch);
+    assert_eq!(counters.get(&0), Some(5 * (BATCH_SIZE as u64 / 3 + 1)));
+    assert_eq!(counters.update(3, 5), 5);
+    assert_eq!(counters.update(3, 5), 10);
+}
+
+#[test]
+#[should_panic(expected = "updates need a merge operator")]
+fn test_update_without_merge_operator() {
+    let mut tree = PalmMap::<KeyType, KeyType>::new(NUM_THREADS);
+    tree.update(0, 1);
+}
+
+#[test]
+fn test_conditional() {
+    let mut rng = thread_rng();
+    let outcome = |success, current| Response::Outcome(Outcome { success, current });
+
+    let mut tree = PalmMap::<KeyType, KeyType>::new(NUM_THREADS);
+    let mut map = BTreeMap::new();
+    for _ in 0..NUM_BATCHES / 16 {
+        let mut ref_result = vec![];
+        let mut batch = vec![];
+        for j in 0..BATCH_SIZE {
+            // few keys and few values, so that conditions both hold and fail
+            let k = rng.gen_range(0, KEY_RANGE / 100);
+            let v = rng.gen_range(0, 4);
+            let query = match j % 4 {
+                0 => {
+                    ref_result.push((Query::Deletion { k }, map.remove(&k).into()));
+                    Query::Deletion { k }
+                }
+                1 => {
+                    let query = Query::InsertIfAbsent { k, v };
+                    match map.get(&k) {
+                        Some(current) => {
+                            ref_result.push((query.clone(), outcome(false, Some(*current))))
+                        }
+                        None => {
+                            map.insert(k, v);
+                            ref_result.push((query.clone(), outcome(true, Some(v))));
+                        }
+                    }
+                    query
+                }
+                _ => {
+                    let new = rng.gen_range(0, 4);
+                    let query = Query::CompareAndSwap {
+                        k,
+                        expected: v,
+                        new,
+                    };
+                    match map.get_mut(&k) {
+                        Some(current) if *current == v => {
+                            *current = new;
+                            ref_result.push((query.clone(), outcome(true, Some(new))));
+                        }
+                        current => {
+                            ref_result.push((query.clone(), outcome(false, current.cloned())))
+                        }
+                    }
+                    query
+                }
+            };
+            batch.push(query);
+        }
+
+        let result = tree.apply_batch(&batch);
+
+        assert_eq!(ref_result, result);
+        tree.check_invariants().unwrap();
+    }
+
+    // conditions see the writes submitted before them in the same batch
+    let k = KEY_RANGE;
+    let batch = vec![
+        Query::CompareAndSwap {
+            k,
+            expected: 0,
+            new: 1,
+        },
+        Query::InsertIfAbsent { k, v: 1 },
+        Query::InsertIfAbsent { k, v: 2 },
+        Query::CompareAndSwap {
+            k,
+            expected: 1,
+            new: 3,
+        },
+        Query::CompareAndSwap {
+            k,
+            expected: 1,
+            new: 4,
+        },
+        Query::Deletion { k },
+        Query::InsertIfAbsent { k, v: 5 },
+    ];
+    let result: Vec<_> = tree
+        .apply_batch(&batch)
+        .into_iter()
+        .map(|(_, r)| r)
+        .collect();
+    assert_eq!(
+        result,
+        vec![
+            outcome(false, None),
+            outcome(true, Some(1)),
+            outcome(false, Some(1)),
+            outcome(true, Some(3)),
+            outcome(false, Some(3)),
+            Response::Value(Some(3)),
+            outcome(true, Some(5)),
+        ]
+    );
+    assert_eq!(
+        tree.compare_and_swap(k, 5, 6),
+        Outcome {
+            success: true,
+            current: Some(6)
+        }
+    );
+    assert_eq!(
+        tree.insert_if_absent(k, 7),
+        Outcome {
+            success: false,
+            current: Some(6)
+        }
+    );
+}
+
+// keys with a long shared prefix, so that separators are much shorter
+//   than the keys they split
+fn byte_key<K: From<String>>(i: KeyType) -> K {
+    format!("tenant/{:02}/ключ/{:06}", i % 7, i).into()
+}
+
+fn check_byte_keys<K>(num_batches: usize)
+where
+    K: 'static + SearchKey + Clone + std::fmt::Debug + Sync + Send + From<String>,
+{
+    let mut rng = thread_rng();
+
+    let mut tree = PalmMap::<K, KeyType, 8>::new(NUM_THREADS);
+    let mut map = BTreeMap::new();
+    for _ in 0..num_batches {
+        let mut ref_result = vec![];
+        let mut batch = vec![];
+        for j in 0..BATCH_SIZE / 4 {
+            let i = rng.gen_range(0, KEY_RANGE);
+            let k: K = byte_key(i);
+            let query = match j % 8 {
+                0..=3 => {
+                    let v = rng.gen_range(0, KEY_RANGE);
+                    ref_result.push((
+                        Query::Insertion { k: k.clone(), v },
+                        map.insert(k.clone(), v).into(),
+                    ));
+                    Query::Insertion { k, v }
+                }
+                4..=5 => {
+                    ref_result.push((Query::Deletion { k: k.clone() }, map.remove(&k).into()));
+                    Query::Deletion { k }
+                }
+                6 => {
+                    ref_result.push((
+                        Query::Retrieval { k: k.clone() },
+                        map.get(&k).cloned().into(),
+                    ));
+                    Query::Retrieval { k }
+                }
+                _ => {
+                    let hi: K = byte_key(i + 7 * rng.gen_range(0, 20));
+                    let query = Query::Range {
+                        lo: k,
+                        hi,
+                        limit: None,
+                    };
+                    ref_result.push((query.clone(), Response::Value(None)));
+                    query
+                }
+            };
+            batch.push(query);
+        }
+        for (query, response) in ref_result.iter_mut() {
+            if let Query::Range { lo, hi, .. } = query {
+                let found = map
+                    .range(lo.clone()..hi.clone())
+                    .map(|(k, v)| (k.clone(), *v))
+                    .collect();
+                *response = Response::Range(found);
+            }
+        }
+
+        let result = tree.apply_batch(&batch);
+
+        assert_eq!(ref_result, result);
+        tree.check_invariants().unwrap();
+    }
+}
+
+#[test]
+fn test_byte_keys() {
+    check_byte_keys::<Vec<u8>>(NUM_BATCHES / 32);
+    check_byte_keys::<String>(NUM_BATCHES / 32);
+}
+
+fn check_byte_kernels<K>()
+where
+    K: SearchKey + From<String>,
+{
+    let mut rng = thread_rng();
+    let kernels = K::kernels(SimdLevel::detect());
+    let scalar = Kernels::<K>::scalar();
+    let candidates: Vec<K> = (0..60).map(byte_key).collect();
+    for len in 0..=40 {
+        let mut keys: Vec<K> = (0..len).map(|_| byte_key(rng.gen_range(0, 50))).collect();
+        for value in &candidates {
+            assert_eq!(
+                kernels.linear_search(&keys, value),
+                scalar.linear_search(&keys, value)
+            );
+        }
+        keys.sort();
+        for value in &candidates {
+            assert_eq!(
+                kernels.lower_bound(&keys, value),
+                scalar.lower_bound(&keys, value)
+            );
+            assert_eq!(
+                kernels.upper_bound(&keys, value),
+                scalar.upper_bound(&keys, value)
+            );
+        }
+    }
+}
+
+#[test]
+fn test_byte_kernels() {
+    check_byte_kernels::<Vec<u8>>();
+    check_byte_kernels::<String>();
+
+    // borrowed byte slices find the same positions as owned keys
+    let mut rng = thread_rng();
+    for len in 0..=40 {
+        let mut keys: Vec<Vec<u8>> = (0..len).map(|_| byte_key(rng.gen_range(0, 50))).collect();
+        keys.sort();
+        for i in 0..60 {
+            let value: Vec<u8> = byte_key(i);
+            assert_eq!(
+                SortedSearch::<[u8]>::lower_bound(&keys[..], &value[..]),
+                SortedSearch::<Vec<u8>>::lower_bound(&keys[..], &value)
+            );
+            assert_eq!(
+                SortedSearch::<[u8]>::upper_bound(&keys[..], &value[..]),
+                SortedSearch::<Vec<u8>>::upper_bound(&keys[..], &value)
+            );
+        }
+    }
+}
+
+#[test]
+fn test_separator() {
+    let sep = |l: &[u8], r: &[u8]| Vec::separator(&l.to_vec(), &r.to_vec());
+    assert_eq!(sep(b"apple", b"apricot"), b"apr");
+    assert_eq!(sep(b"ab", b"abc"), b"abc");
+    assert_eq!(sep(b"", b"a"), b"a");
+    assert_eq!(sep(b"abc", b"b"), b"b");
+    // never cut inside a multi-byte character
+    let sep = String::separator(&"caf\u{e9}s".to_string(), &"caf\u{ea}".to_string());
+    assert_eq!(sep, "caf\u{ea}");
+    assert_eq!(u32::separator(&3, &10), 10);
 }